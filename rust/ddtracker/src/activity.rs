@@ -0,0 +1,114 @@
+use std::collections::HashSet;
+
+use log::info;
+use rusqlite::{params, Transaction};
+
+use crate::dummies::Dummies;
+use crate::model::ServerList;
+
+// every tick a name is on the list adds the tick's minutes to its hour of the week and to each
+// region it was seen in. hours are UTC and start with monday 00:00, so the website only has to shift
+// them by the viewer's offset. the region with the most minutes is the player's home region. likely
+// dummies don't count, their owner is already there.
+
+/// The epoch was a thursday 00:00, shifted by 72 hours hour 0 is monday 00:00
+const WEEK_OFFSET: i64 = 72;
+const HOURS_PER_WEEK: i64 = 7 * 24;
+
+fn hour_of_week(time: i64) -> i64 {
+    (time / 60 + WEEK_OFFSET) % HOURS_PER_WEEK
+}
+
+pub fn update(
+    tx: &Transaction,
+    servers_data: &ServerList,
+    dummies: &Dummies,
+    now: i64,
+    minutes: i64,
+) -> rusqlite::Result<()> {
+    let mut names: HashSet<&str> = HashSet::new();
+    let mut regions: HashSet<(&str, &str)> = HashSet::new();
+    for server in &servers_data.servers {
+        let address = server.address();
+        let location = server.location.as_deref();
+        for client in &server.info.clients {
+            if address.is_some_and(|address| dummies.contains(&(address, client.name.as_str()))) {
+                continue;
+            }
+            names.insert(client.name.as_str());
+            if let Some(location) = location {
+                regions.insert((client.name.as_str(), location));
+            }
+        }
+    }
+
+    let hour = hour_of_week(now);
+    let mut activity_stmt = tx.prepare_cached(
+        "INSERT INTO player_activity (name, hour, minutes) VALUES (?, ?, ?)
+        ON CONFLICT (name, hour) DO UPDATE SET minutes = minutes + excluded.minutes",
+    )?;
+    for name in &names {
+        activity_stmt.execute(params![name, hour, minutes])?;
+    }
+
+    let mut region_stmt = tx.prepare_cached(
+        "INSERT INTO player_regions (name, region, minutes, last_seen) VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT (name, region) DO UPDATE SET minutes = minutes + excluded.minutes, last_seen = excluded.last_seen",
+    )?;
+    for (name, region) in &regions {
+        region_stmt.execute(params![name, region, minutes, now])?;
+    }
+
+    info!(
+        "Activity: {} players in hour {}, {} player regions",
+        names.len(),
+        hour,
+        regions.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::{migrations, model};
+
+    #[test]
+    fn ticks_add_their_minutes() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn, false).unwrap();
+        let (servers, _) = model::parse(
+            r#"{"servers":[
+                {"addresses":["tw-0.6+udp://1.1.1.1:8303"],"location":"eu:de","info":{"clients":[{"name":"Tee"}]}},
+                {"addresses":["tw-0.6+udp://2.2.2.2:8303"],"location":"as:cn","info":{"clients":[{"name":"Tee"}]}}
+            ]}"#,
+        )
+        .unwrap();
+
+        // monday 2025-01-06 10:00 UTC, then five minutes later on a five minute schedule
+        let monday = 20_094 * 24 * 60 + 10 * 60;
+        let tx = conn.transaction().unwrap();
+        update(&tx, &servers, &Dummies::new(), monday, 1).unwrap();
+        update(&tx, &servers, &Dummies::new(), monday + 5, 5).unwrap();
+        tx.commit().unwrap();
+
+        let activity: (i64, i64) = conn
+            .query_row(
+                "SELECT hour, minutes FROM player_activity WHERE name = 'Tee'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(activity, (10, 6));
+        let regions: i64 = conn
+            .query_row(
+                "SELECT SUM(minutes) FROM player_regions WHERE name = 'Tee'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(regions, 12);
+    }
+}
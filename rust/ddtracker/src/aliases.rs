@@ -0,0 +1,370 @@
+use std::collections::{HashMap, HashSet};
+
+use log::info;
+use rusqlite::{params, Connection, Transaction};
+
+use crate::model::{Client, ServerList};
+
+// a rename looks like a name leaving a server and a new one joining it in the next tick with the same
+// clan, country and skin, while the old name isn't online anywhere else and the new one wasn't
+// online before. a leaver and a joiner only pair up when they match nobody else on that server.
+// every sighting is kept in `alias_evidence`, the pair in `alias_links` is pending until an admin
+// confirms or rejects it with `ddtracker alias`. decisions go into `alias_decisions` by name, which no
+// tracker owns, so they survive a replay. the website merges the histories of confirmed aliases.
+// `alias_clients` holds last tick's clients to tell who left and who joined.
+
+const COMMAND_USAGE: &str = "Usage: ddtracker alias list [pending|confirmed|rejected]
+       ddtracker alias show <id>
+       ddtracker alias confirm <id>
+       ddtracker alias reject <id>";
+
+/// Minutes since last tick after which leaving and joining can't be told apart from coming back
+const MAX_GAP: i64 = 2;
+
+/// What a renamed player keeps, clan, country and skin in its stored form
+type Setup = (String, i64, Option<String>);
+
+fn setup(client: &Client) -> Setup {
+    (
+        client.clan.clone(),
+        client.country,
+        client.skin.as_ref().map(|skin| skin.data()),
+    )
+}
+
+pub fn update(tx: &Transaction, servers_data: &ServerList, now: i64) -> rusqlite::Result<()> {
+    let last_tick: Option<i64> =
+        tx.query_row("SELECT MAX(time) FROM alias_clients", [], |row| row.get(0))?;
+    let mut previous: HashMap<String, HashMap<String, Setup>> = HashMap::new();
+    if last_tick.is_some_and(|last| now - last <= MAX_GAP) {
+        let mut stmt =
+            tx.prepare_cached("SELECT address, name, clan, country, skin FROM alias_clients")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            previous
+                .entry(row.get(0)?)
+                .or_default()
+                .insert(row.get(1)?, (row.get(2)?, row.get(3)?, row.get(4)?));
+        }
+    }
+    let previous_names: HashSet<&str> = previous
+        .values()
+        .flat_map(|clients| clients.keys().map(String::as_str))
+        .collect();
+    let current_names: HashSet<&str> = servers_data
+        .servers
+        .iter()
+        .flat_map(|server| {
+            server
+                .info
+                .clients
+                .iter()
+                .map(|client| client.name.as_str())
+        })
+        .collect();
+
+    let mut renames: Vec<(&str, &Client, &str, &str)> = Vec::new();
+    let mut seen: HashSet<&str> = HashSet::new();
+    for server in &servers_data.servers {
+        let Some(address) = server.address() else {
+            continue;
+        };
+        if !seen.insert(address) {
+            continue;
+        }
+        let Some(before) = previous.get(address) else {
+            continue;
+        };
+        let left: Vec<(&str, &Setup)> = before
+            .iter()
+            .filter(|(name, _)| !current_names.contains(name.as_str()))
+            .map(|(name, setup)| (name.as_str(), setup))
+            .collect();
+        let joined: Vec<(&Client, Setup)> = server
+            .info
+            .clients
+            .iter()
+            .filter(|client| {
+                !previous_names.contains(client.name.as_str()) && !client.is_anonymous()
+            })
+            .map(|client| (client, setup(client)))
+            .collect();
+
+        for (old_name, old_setup) in &left {
+            let mut matches = joined.iter().filter(|(_, setup)| setup == *old_setup);
+            let (Some((client, new_setup)), None) = (matches.next(), matches.next()) else {
+                continue;
+            };
+            if left.iter().filter(|(_, setup)| *setup == new_setup).count() == 1 {
+                renames.push((old_name, client, address, server.info.map.name.as_str()));
+            }
+        }
+    }
+
+    let mut link_stmt = tx.prepare_cached(
+        "INSERT INTO alias_links (old_name, new_name, renames, first_seen, last_seen) VALUES (?1, ?2, 1, ?3, ?3)
+        ON CONFLICT (old_name, new_name) DO UPDATE SET renames = renames + 1, last_seen = excluded.last_seen
+        RETURNING id",
+    )?;
+    let mut evidence_stmt = tx.prepare_cached(
+        "INSERT INTO alias_evidence (link_id, time, address, map, clan, country, skin) VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )?;
+    for (old_name, client, address, map) in &renames {
+        let id: i64 = link_stmt.query_row(params![old_name, client.name, now], |row| row.get(0))?;
+        evidence_stmt.execute(params![
+            id,
+            now,
+            address,
+            map,
+            client.clan,
+            client.country,
+            client.skin.as_ref().map(|skin| skin.data())
+        ])?;
+    }
+
+    tx.prepare_cached("DELETE FROM alias_clients")?
+        .execute([])?;
+    let mut client_stmt = tx.prepare_cached(
+        "INSERT OR IGNORE INTO alias_clients (address, name, clan, country, skin, time) VALUES (?, ?, ?, ?, ?, ?)",
+    )?;
+    for server in &servers_data.servers {
+        let Some(address) = server.address() else {
+            continue;
+        };
+        for client in &server.info.clients {
+            let (clan, country, skin) = setup(client);
+            client_stmt.execute(params![address, client.name, clan, country, skin, now])?;
+        }
+    }
+
+    info!("Aliases: {} likely renames", renames.len());
+    Ok(())
+}
+
+pub fn command(conn: &Connection, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let parse_id = |id: &str| -> Result<i64, String> {
+        id.parse().map_err(|_| format!("Invalid alias id {}", id))
+    };
+    match args.as_slice() {
+        ["list", rest @ ..] if rest.len() <= 1 => {
+            let status = rest.first().copied().unwrap_or("pending");
+            if !["pending", "confirmed", "rejected"].contains(&status) {
+                return Err(format!(
+                    "Unknown alias status {}, expected pending, confirmed or rejected",
+                    status
+                )
+                .into());
+            }
+            let mut stmt = conn.prepare(
+                "SELECT l.id, l.old_name, l.new_name, l.renames, l.first_seen, l.last_seen FROM alias_links l
+                LEFT JOIN alias_decisions d ON d.old_name = l.old_name AND d.new_name = l.new_name
+                WHERE coalesce(d.status, 'pending') = ? ORDER BY l.renames DESC, l.last_seen DESC",
+            )?;
+            let mut rows = stmt.query(params![status])?;
+            while let Some(row) = rows.next()? {
+                println!(
+                    "{}\t{}\t{}\t{}x\t{}\t{}",
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, i64>(3)?,
+                    format_time(row.get(4)?),
+                    format_time(row.get(5)?)
+                );
+            }
+        }
+        ["show", id] => {
+            let id = parse_id(id)?;
+            let (old_name, new_name, status): (String, String, String) = conn
+                .query_row(
+                    "SELECT l.old_name, l.new_name, coalesce(d.status, 'pending') FROM alias_links l
+                    LEFT JOIN alias_decisions d ON d.old_name = l.old_name AND d.new_name = l.new_name WHERE l.id = ?",
+                    params![id],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .map_err(|_| format!("No alias with id {}", id))?;
+            println!("{} -> {} ({})", old_name, new_name, status);
+            let mut stmt = conn.prepare(
+                "SELECT time, address, map, clan, country, skin FROM alias_evidence WHERE link_id = ? ORDER BY time",
+            )?;
+            let mut rows = stmt.query(params![id])?;
+            while let Some(row) = rows.next()? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    format_time(row.get(0)?),
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, i64>(4)?,
+                    row.get::<_, Option<String>>(5)?.unwrap_or_default()
+                );
+            }
+        }
+        [action @ ("confirm" | "reject"), id] => {
+            let id = parse_id(id)?;
+            let status = if *action == "confirm" {
+                "confirmed"
+            } else {
+                "rejected"
+            };
+            if conn.execute(
+                "INSERT INTO alias_decisions (old_name, new_name, status, decided) SELECT old_name, new_name, ?, ? FROM alias_links WHERE id = ?
+                ON CONFLICT (old_name, new_name) DO UPDATE SET status = excluded.status, decided = excluded.decided",
+                params![status, chrono::Utc::now().timestamp() / 60, id],
+            )? == 0
+            {
+                return Err(format!("No alias with id {}", id).into());
+            }
+            println!("Alias {} {}", id, status);
+        }
+        _ => {
+            eprintln!("{}", COMMAND_USAGE);
+            return Err("Invalid alias command".into());
+        }
+    }
+    Ok(())
+}
+
+/// Minutes since the epoch as a readable UTC time
+fn format_time(minutes: i64) -> String {
+    chrono::DateTime::from_timestamp(minutes * 60, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| minutes.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{migrations, model};
+
+    /// One server with the clients, each a name, clan and skin
+    fn tick(conn: &mut Connection, clients: &[(&str, &str, &str)], now: i64) {
+        let clients: Vec<String> = clients
+            .iter()
+            .map(|(name, clan, skin)| {
+                format!(
+                    r#"{{"name":"{}","clan":"{}","country":276,"skin":{{"name":"{}"}}}}"#,
+                    name, clan, skin
+                )
+            })
+            .collect();
+        let (servers, _) = model::parse(&format!(
+            r#"{{"servers":[{{"addresses":["tw-0.6+udp://1.1.1.1:8303"],"location":"eu:de","info":{{"map":{{"name":"Kobra"}},"clients":[{}]}}}}]}}"#,
+            clients.join(",")
+        ))
+        .unwrap();
+        let tx = conn.transaction().unwrap();
+        update(&tx, &servers, now).unwrap();
+        tx.commit().unwrap();
+    }
+
+    fn links(conn: &Connection) -> Vec<(String, String, i64)> {
+        conn.prepare("SELECT old_name, new_name, renames FROM alias_links ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn status(conn: &Connection) -> Option<String> {
+        conn.query_row(
+            "SELECT status FROM alias_decisions WHERE old_name = 'Old' AND new_name = 'New'",
+            [],
+            |row| row.get(0),
+        )
+        .ok()
+    }
+
+    fn run(conn: &Connection, args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        command(conn, &args)
+    }
+
+    #[test]
+    fn renames_are_detected_only_when_unambiguous() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn, false).unwrap();
+
+        tick(
+            &mut conn,
+            &[("Old", "Clan", "pinky"), ("Stay", "", "cammo")],
+            100,
+        );
+        tick(
+            &mut conn,
+            &[("New", "Clan", "pinky"), ("Stay", "", "cammo")],
+            101,
+        );
+        // two leavers look the same, either could be the joiner
+        tick(
+            &mut conn,
+            &[("Twin1", "T", "coala"), ("Twin2", "T", "coala")],
+            102,
+        );
+        tick(&mut conn, &[("Twin3", "T", "coala")], 103);
+        // too long since the last tick to tell a rename from coming back
+        tick(&mut conn, &[("Old", "Clan", "pinky")], 110);
+        tick(&mut conn, &[("New", "Clan", "pinky")], 120);
+        // a different skin is someone else
+        tick(&mut conn, &[("New", "Clan", "pinky")], 121);
+        tick(&mut conn, &[("Other", "Clan", "santa")], 122);
+        tick(&mut conn, &[("Old", "Clan", "pinky")], 123);
+        tick(&mut conn, &[("New", "Clan", "pinky")], 124);
+
+        assert_eq!(
+            links(&conn),
+            vec![("Old".to_string(), "New".to_string(), 2)]
+        );
+        let evidence: Vec<(i64, String, String)> = conn
+            .prepare("SELECT time, map, clan FROM alias_evidence ORDER BY time")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            evidence,
+            vec![
+                (101, "Kobra".to_string(), "Clan".to_string()),
+                (124, "Kobra".to_string(), "Clan".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn decisions_outlive_the_links() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn, false).unwrap();
+        tick(&mut conn, &[("Old", "Clan", "pinky")], 100);
+        tick(&mut conn, &[("New", "Clan", "pinky")], 101);
+        let id: i64 = conn
+            .query_row("SELECT id FROM alias_links", [], |row| row.get(0))
+            .unwrap();
+        let id = id.to_string();
+
+        assert_eq!(status(&conn), None);
+        run(&conn, &["list"]).unwrap();
+        run(&conn, &["show", &id]).unwrap();
+        run(&conn, &["confirm", &id]).unwrap();
+        assert_eq!(status(&conn).as_deref(), Some("confirmed"));
+        run(&conn, &["reject", &id]).unwrap();
+        assert_eq!(status(&conn).as_deref(), Some("rejected"));
+
+        // a replay rebuilds the links with new ids, the decision stays with the names
+        conn.execute_batch(
+            "DELETE FROM alias_evidence; DELETE FROM alias_links; DELETE FROM alias_clients;",
+        )
+        .unwrap();
+        tick(&mut conn, &[("Old", "Clan", "pinky")], 200);
+        tick(&mut conn, &[("New", "Clan", "pinky")], 201);
+        assert_eq!(links(&conn).len(), 1);
+        assert_eq!(status(&conn).as_deref(), Some("rejected"));
+
+        assert!(run(&conn, &["confirm", "9999"]).is_err());
+        assert!(run(&conn, &["show", "nope"]).is_err());
+        assert!(run(&conn, &["list", "maybe"]).is_err());
+        assert!(run(&conn, &["merge", &id]).is_err());
+    }
+}
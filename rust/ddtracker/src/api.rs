@@ -0,0 +1,555 @@
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{FromRef, RawQuery, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use log::{error, info};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+
+use crate::events::EventSender;
+use crate::model::Skin;
+
+// small read-only query api for local consumers, so they don't have to open the database
+// themselves. skin responses use the same shapes as the website's /api/playerskin and
+// /api/playerskinbatch, times are unix timestamps in milliseconds. /events streams the tracker's
+// change events as server-sent events.
+
+type Db = Arc<Mutex<Connection>>;
+
+#[derive(Clone)]
+struct ApiState {
+    db: Db,
+    events: EventSender,
+}
+
+impl FromRef<ApiState> for Db {
+    fn from_ref(state: &ApiState) -> Self {
+        state.db.clone()
+    }
+}
+
+impl FromRef<ApiState> for EventSender {
+    fn from_ref(state: &ApiState) -> Self {
+        state.events.clone()
+    }
+}
+
+pub async fn bind(
+    listen: &str,
+    database: &str,
+    events: EventSender,
+) -> Result<impl std::future::Future<Output = ()>, Box<dyn std::error::Error>> {
+    let conn = Connection::open_with_flags(
+        database,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    let app = router(Arc::new(Mutex::new(conn)), events);
+
+    let listener = TcpListener::bind(listen).await?;
+    info!("API listening on {}", listen);
+
+    Ok(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("API server stopped: {}", e);
+        }
+    })
+}
+
+fn router(db: Db, events: EventSender) -> Router {
+    Router::new()
+        .route("/playerskin", get(player_skin))
+        .route("/playerskinbatch", get(player_skin_batch))
+        .route("/lastseen", get(last_seen))
+        .route("/online", get(online))
+        .route("/events", get(event_stream))
+        .with_state(ApiState { db, events })
+}
+
+struct Query(Vec<(String, String)>);
+
+impl Query {
+    fn parse(raw: Option<String>) -> Self {
+        Query(
+            form_urlencoded::parse(raw.unwrap_or_default().as_bytes())
+                .into_owned()
+                .collect(),
+        )
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .filter(|v| !v.is_empty())
+    }
+
+    fn get_all(&self, key: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+            .collect()
+    }
+}
+
+/// Runs a query on the shared connection off the async runtime
+async fn with_db<T: Send + 'static>(
+    db: Db,
+    f: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+) -> Result<T, Response> {
+    tokio::task::spawn_blocking(move || {
+        let conn = db.lock().unwrap_or_else(|e| e.into_inner());
+        f(&conn)
+    })
+    .await
+    .map_err(|e| internal_error(e.to_string()))?
+    .map_err(|e| internal_error(e.to_string()))
+}
+
+fn internal_error(message: String) -> Response {
+    error!("API query failed: {}", message);
+    (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+}
+
+fn bad_request(message: &'static str) -> Response {
+    (StatusCode::BAD_REQUEST, message).into_response()
+}
+
+/// The region the player and its confirmed aliases spent the most minutes in, like the website's
+/// `getHomeRegion`
+fn home_region(conn: &Connection, name: &str) -> rusqlite::Result<Option<String>> {
+    conn.prepare_cached(
+        "WITH RECURSIVE aliases(name) AS (
+            SELECT ?1
+            UNION
+            SELECT CASE WHEN d.old_name = a.name THEN d.new_name ELSE d.old_name END
+            FROM alias_decisions d JOIN aliases a ON d.old_name = a.name OR d.new_name = a.name
+            WHERE d.status = 'confirmed'
+        )
+        SELECT region FROM player_regions WHERE name IN aliases GROUP BY region ORDER BY SUM(minutes) DESC, MAX(last_seen) DESC LIMIT 1",
+    )?
+    .query_row(params![name], |row| row.get(0))
+    .optional()
+}
+
+/// Skin of a player in a region, the same lookups in the same order as the website's `getSkin`.
+/// Full regions (`as:cn`) match exactly, a bare prefix (`as`) matches the most recent skin in any
+/// region under it, names in a prefix lookup match with `LIKE` like on the website. Without a region
+/// the player's home region is tried before every region.
+fn get_skin(
+    conn: &Connection,
+    name: &str,
+    region: Option<&str>,
+) -> rusqlite::Result<Option<Value>> {
+    let skin = |row: &rusqlite::Row| {
+        Ok(Skin {
+            name: row.get(0)?,
+            color_body: row.get(1)?,
+            color_feet: row.get(2)?,
+        })
+    };
+    let in_region = |region: &str| {
+        conn.prepare_cached(
+            "SELECT s.name, s.body, s.feet FROM clients c JOIN skins s ON s.id = c.current_skin_id WHERE c.name = ? AND c.region = ?",
+        )?
+        .query_row(params![name, region], skin)
+        .optional()
+    };
+
+    let found = match region {
+        Some(region) if region.contains(':') => in_region(region)?,
+        Some(region) => conn
+            .prepare_cached(
+                "SELECT s.name, s.body, s.feet FROM clients c JOIN skins s ON s.id = c.current_skin_id WHERE c.name LIKE ? AND c.region LIKE ? ORDER BY c.current_skin_time DESC LIMIT 1",
+            )?
+            .query_row(params![name, format!("{}%", region)], skin)
+            .optional()?,
+        None => {
+            let at_home = match home_region(conn, name)? {
+                Some(home) => in_region(&home)?,
+                None => None,
+            };
+            match at_home {
+                Some(skin) => Some(skin),
+                None => conn
+                    .prepare_cached(
+                        "SELECT s.name, s.body, s.feet FROM clients c JOIN skins s ON s.id = c.current_skin_id WHERE c.name = ? ORDER BY c.current_skin_time DESC LIMIT 1",
+                    )?
+                    .query_row(params![name], skin)
+                    .optional()?,
+            }
+        }
+    };
+    Ok(found.and_then(|skin| serde_json::from_str(&skin.data()).ok()))
+}
+
+/// `get_skin`, with `fallback` a miss in the given region looks the player up without one like the
+/// website's /api/playerskin does
+fn find_skin(
+    conn: &Connection,
+    name: &str,
+    region: Option<&str>,
+    fallback: bool,
+) -> rusqlite::Result<Option<Value>> {
+    match get_skin(conn, name, region)? {
+        None if fallback && region.is_some() => get_skin(conn, name, None),
+        found => Ok(found),
+    }
+}
+
+async fn player_skin(State(db): State<Db>, RawQuery(query): RawQuery) -> Response {
+    let query = Query::parse(query);
+    let Some(name) = query.get("name").map(str::to_string) else {
+        return bad_request("Bad Request");
+    };
+    let region = query.get("region").map(str::to_string);
+    let fallback = query.get("fallback").is_some();
+
+    match with_db(db, move |conn| {
+        find_skin(conn, &name, region.as_deref(), fallback)
+    })
+    .await
+    {
+        Ok(Some(skin)) => Json(skin).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Not Found").into_response(),
+        Err(response) => response,
+    }
+}
+
+async fn player_skin_batch(State(db): State<Db>, RawQuery(query): RawQuery) -> Response {
+    let query = Query::parse(query);
+    let names: Vec<String> = query.get_all("n").into_iter().map(str::to_string).collect();
+    if names.is_empty() {
+        return bad_request("Bad Request: names array cannot be empty");
+    }
+    if names.len() > 64 {
+        return bad_request("Bad Request: names array length cannot exceed 64");
+    }
+    let region = query.get("region").map(str::to_string);
+    let fallback = query.get("fallback").is_some();
+
+    match with_db(db, move |conn| {
+        names
+            .iter()
+            .map(|name| {
+                Ok(find_skin(conn, name, region.as_deref(), fallback)?
+                    .unwrap_or_else(|| Value::Object(Default::default())))
+            })
+            .collect::<rusqlite::Result<Vec<Value>>>()
+    })
+    .await
+    {
+        Ok(skins) => Json(skins).into_response(),
+        Err(response) => response,
+    }
+}
+
+#[derive(Serialize)]
+struct Presence {
+    name: String,
+    address: String,
+    map: String,
+    region: String,
+    since: i64,
+    #[serde(rename = "lastSeen")]
+    last_seen: i64,
+    online: bool,
+}
+
+fn presence_row(row: &rusqlite::Row) -> rusqlite::Result<Presence> {
+    Ok(Presence {
+        name: row.get(0)?,
+        address: row.get(1)?,
+        map: row.get(2)?,
+        region: row.get(3)?,
+        since: row.get::<_, i64>(4)? * 60000,
+        last_seen: row.get::<_, i64>(5)? * 60000,
+        online: row.get::<_, i64>(6)? != 0,
+    })
+}
+
+async fn last_seen(State(db): State<Db>, RawQuery(query): RawQuery) -> Response {
+    let query = Query::parse(query);
+    let Some(name) = query.get("name").map(str::to_string) else {
+        return bad_request("Bad Request");
+    };
+
+    match with_db(db, move |conn| {
+        conn.prepare_cached(
+            "SELECT name, address, map, region, start_time, end_time, online FROM sessions WHERE name = ? ORDER BY end_time DESC, id DESC LIMIT 1",
+        )?
+        .query_row(params![name], presence_row)
+        .optional()
+    })
+    .await
+    {
+        Ok(Some(presence)) => Json(presence).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Not Found").into_response(),
+        Err(response) => response,
+    }
+}
+
+/// Everyone online as of the latest tick, or only the sessions of `name`
+async fn online(State(db): State<Db>, RawQuery(query): RawQuery) -> Response {
+    let query = Query::parse(query);
+    let name = query.get("name").map(str::to_string);
+
+    match with_db(db, move |conn| match name {
+        Some(name) => conn
+            .prepare_cached(
+                "SELECT name, address, map, region, start_time, end_time, online FROM sessions WHERE online = 1 AND name = ? AND end_time >= (SELECT MAX(time) FROM ticks)",
+            )?
+            .query_map(params![name], presence_row)?
+            .collect::<rusqlite::Result<Vec<_>>>(),
+        None => conn
+            .prepare_cached(
+                "SELECT name, address, map, region, start_time, end_time, online FROM sessions WHERE online = 1 AND end_time >= (SELECT MAX(time) FROM ticks)",
+            )?
+            .query_map([], presence_row)?
+            .collect::<rusqlite::Result<Vec<_>>>(),
+    })
+    .await
+    {
+        Ok(sessions) => Json(sessions).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// Change events as they are published, optionally only the given `type`s and the events of the
+/// given player `name`s
+async fn event_stream(State(events): State<EventSender>, RawQuery(query): RawQuery) -> Response {
+    let query = Query::parse(query);
+    let types: HashSet<String> = query
+        .get_all("type")
+        .into_iter()
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .collect();
+    let names: HashSet<String> = query
+        .get_all("name")
+        .into_iter()
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    let stream = BroadcastStream::new(events.subscribe()).filter_map(move |event| {
+        let event = match event {
+            Ok(event) => event,
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                return Some(Ok::<_, Infallible>(
+                    SseEvent::default().comment(format!("missed {} events", missed)),
+                ))
+            }
+        };
+        if !types.is_empty() && !types.contains(event.kind.name()) {
+            return None;
+        }
+        if !names.is_empty() && !event.kind.player().is_some_and(|name| names.contains(name)) {
+            return None;
+        }
+        let data = serde_json::to_string(&event).ok()?;
+        Some(Ok(SseEvent::default().event(event.kind.name()).data(data)))
+    });
+
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::sync::broadcast;
+
+    use super::*;
+    use crate::events::{Event, EventKind};
+    use crate::migrations;
+
+    /// The api over a database with the given rows, its base url
+    async fn serve(sql: &str) -> String {
+        serve_events(sql, broadcast::channel(16).0).await
+    }
+
+    async fn serve_events(sql: &str, events: EventSender) -> String {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn, false).unwrap();
+        conn.execute_batch(sql).unwrap();
+        let app = router(Arc::new(Mutex::new(conn)), events);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", address)
+    }
+
+    async fn get(url: String) -> (StatusCode, Option<Value>) {
+        let response = reqwest::get(url).await.unwrap();
+        let status = StatusCode::from_u16(response.status().as_u16()).unwrap();
+        let body = response.text().await.unwrap();
+        (status, serde_json::from_str(&body).ok())
+    }
+
+    // Tee plays mostly in eu:de, but was last seen in as:cn with another skin. Old is a confirmed
+    // alias whose minutes make as:cn the home region of Renamed.
+    const SKINS: &str = "
+        INSERT INTO skins (id, name, body, feet) VALUES (1, 'pinky', NULL, NULL), (2, 'cammo', 1, 2), (3, 'coala', NULL, NULL);
+        INSERT INTO clients (name, region, current_skin_id, current_skin_time) VALUES
+            ('Tee', 'eu:de', 1, 10), ('Tee', 'as:cn', 2, 20),
+            ('Renamed', 'eu:de', 1, 30), ('Renamed', 'as:cn', 3, 5);
+        INSERT INTO player_regions (name, region, minutes, last_seen) VALUES
+            ('Tee', 'eu:de', 100, 10), ('Tee', 'as:cn', 5, 20),
+            ('Renamed', 'eu:de', 3, 30), ('Old', 'as:cn', 50, 5);
+        INSERT INTO alias_decisions (old_name, new_name, status, decided) VALUES ('Old', 'Renamed', 'confirmed', 0);
+    ";
+
+    #[tokio::test]
+    async fn skins_are_looked_up_like_the_website() {
+        let url = serve(SKINS).await;
+        let pinky = json!({"n": "pinky"});
+        let cammo = json!({"n": "cammo", "b": 1, "f": 2});
+        let coala = json!({"n": "coala"});
+        // query, like getSkin with the website's fallback
+        let cases = [
+            ("name=Tee", Some(&pinky)),
+            ("name=Renamed", Some(&coala)),
+            ("name=Tee&region=as:cn", Some(&cammo)),
+            ("name=Tee&region=eu", Some(&pinky)),
+            ("name=tee&region=as", Some(&cammo)),
+            ("name=T_e&region=eu", Some(&pinky)),
+            ("name=Tee&region=na:us", None),
+            ("name=Tee&region=na:us&fallback=1", Some(&pinky)),
+            ("name=Tee&region=na&fallback=1", Some(&pinky)),
+            ("name=Nobody", None),
+        ];
+
+        for (query, expected) in cases {
+            let (status, single) = get(format!("{}/playerskin?{}", url, query)).await;
+            assert_eq!(single.as_ref(), expected, "{}", query);
+            let code = if expected.is_some() { 200 } else { 404 };
+            assert_eq!(status.as_u16(), code, "{}", query);
+
+            let batch_query = query.replacen("name=", "n=", 1);
+            let (_, batch) = get(format!("{}/playerskinbatch?{}&n=Nobody", url, batch_query)).await;
+            let empty = json!({});
+            assert_eq!(
+                batch,
+                Some(json!([expected.unwrap_or(&empty), empty])),
+                "{}",
+                query
+            );
+        }
+
+        let (status, _) = get(format!("{}/playerskinbatch", url)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn presence_comes_from_the_sessions() {
+        let url = serve(
+            "
+            INSERT INTO ticks (time) VALUES (100), (101);
+            INSERT INTO sessions (name, address, map, region, start_time, end_time, online) VALUES
+                ('Tee', '1.1.1.1:8303', 'Kobra', 'eu:de', 90, 95, 0),
+                ('Tee', '2.2.2.2:8303', 'Multeasymap', 'eu:de', 96, 101, 1),
+                ('other', '1.1.1.1:8303', 'Kobra', 'eu:de', 90, 101, 1),
+                ('gone', '1.1.1.1:8303', 'Kobra', 'eu:de', 90, 99, 1);
+            ",
+        )
+        .await;
+
+        let (_, seen) = get(format!("{}/lastseen?name=Tee", url)).await;
+        assert_eq!(
+            seen,
+            Some(json!({
+                "name": "Tee",
+                "address": "2.2.2.2:8303",
+                "map": "Multeasymap",
+                "region": "eu:de",
+                "since": 96 * 60000,
+                "lastSeen": 101 * 60000,
+                "online": true
+            }))
+        );
+        let (status, _) = get(format!("{}/lastseen?name=Nobody", url)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // a session still open from before the latest tick isn't online
+        let (_, online) = get(format!("{}/online", url)).await;
+        let mut names: Vec<String> = online
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|presence| presence["name"].as_str().unwrap().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["Tee", "other"]);
+        let (_, online) = get(format!("{}/online?name=other", url)).await;
+        assert_eq!(online.unwrap().as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn event_stream_filters_by_type_and_name() {
+        let (events, _) = broadcast::channel(16);
+        let base = serve_events("", events.clone()).await;
+        let mut response = reqwest::get(format!("{}/events?type=player_joined&name=Tee", base))
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+
+        let player = |name: &str, joined: bool| {
+            let (name, address, map, region) = (
+                name.to_string(),
+                "tw-0.6+udp://1.1.1.1:8303".to_string(),
+                "Kobra".to_string(),
+                "eu:de".to_string(),
+            );
+            let kind = if joined {
+                EventKind::PlayerJoined {
+                    name,
+                    address,
+                    map,
+                    region,
+                }
+            } else {
+                EventKind::PlayerLeft {
+                    name,
+                    address,
+                    map,
+                    region,
+                }
+            };
+            Event { time: 60000, kind }
+        };
+        events.send(player("Other", true)).unwrap();
+        events.send(player("Tee", false)).unwrap();
+        events.send(player("Tee", true)).unwrap();
+
+        let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), response.chunk())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let body = String::from_utf8(chunk.to_vec()).unwrap();
+        let data = body
+            .lines()
+            .find_map(|line| line.strip_prefix("data: "))
+            .unwrap();
+        assert!(body.starts_with("event: player_joined\n"));
+        let event: Value = serde_json::from_str(data).unwrap();
+        assert_eq!(event["type"], "player_joined");
+        assert_eq!(event["name"], "Tee");
+        assert_eq!(event["time"], 60000);
+    }
+}
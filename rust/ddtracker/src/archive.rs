@@ -0,0 +1,94 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::fetch::Fetched;
+
+// every fetched list is kept as one json line per tick in daily files, `YYYY-MM-DD.jsonl.zst` (utc).
+// each line is compressed as its own zstd frame and appended, concatenated frames read back as one
+// stream and a crash can only cut off the last line. the body is the master's servers.json as
+// received when the tick used a single master, otherwise the merged list in the same shape.
+
+pub const EXTENSION: &str = ".jsonl.zst";
+const COMPRESSION_LEVEL: i32 = 9;
+
+#[derive(Serialize)]
+struct RecordRef<'a> {
+    /// Minutes since the epoch, like everywhere in the database
+    time: i64,
+    sources: &'a [String],
+    body: &'a RawValue,
+}
+
+#[derive(Deserialize)]
+pub struct Record {
+    pub time: i64,
+    pub body: Box<RawValue>,
+}
+
+pub struct Archive {
+    dir: PathBuf,
+}
+
+impl Archive {
+    pub fn new(dir: &str) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self { dir: dir.into() })
+    }
+
+    /// Appends a tick's list to the file of its day
+    pub fn store(&self, now: i64, fetched: &Fetched) -> io::Result<()> {
+        let merged;
+        let body = match &fetched.raw {
+            Some(raw) => raw.as_str(),
+            None => {
+                merged = serde_json::to_string(&fetched.servers)?;
+                merged.as_str()
+            }
+        };
+        let line = serde_json::to_string(&RecordRef {
+            time: now,
+            sources: &fetched.sources,
+            body: serde_json::from_str::<&RawValue>(body)?,
+        })? + "\n";
+
+        let day = Utc
+            .timestamp_opt(now * 60, 0)
+            .single()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "time out of range"))?
+            .format("%Y-%m-%d");
+        let frame = zstd::stream::encode_all(line.as_bytes(), COMPRESSION_LEVEL)?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(format!("{}{}", day, EXTENSION)))?
+            .write_all(&frame)
+    }
+}
+
+/// Daily files of an archive directory, oldest first
+pub fn files(dir: &str) -> io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.ends_with(EXTENSION))
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Records of a daily file in the order they were stored
+pub fn read(path: &Path) -> io::Result<impl Iterator<Item = io::Result<Record>>> {
+    let reader = BufReader::new(zstd::stream::read::Decoder::new(File::open(path)?)?);
+    Ok(reader.lines().map(|line| {
+        let line = line?;
+        serde_json::from_str(&line).map_err(io::Error::from)
+    }))
+}
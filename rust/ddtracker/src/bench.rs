@@ -0,0 +1,291 @@
+use std::path::Path;
+use std::time::Instant;
+
+use rusqlite::Connection;
+
+use crate::archive;
+use crate::config::Config;
+use crate::ingest::{self, Tracker};
+use crate::migrations;
+use crate::model::{self, Client, MapInfo, Server, ServerInfo, ServerList, Skin};
+
+// times the trackers on a recorded list, a servers.json saved from a master or a daily file of the
+// archive, of which the busiest tick is used. without one it falls back to a list generated here.
+// every tick runs on an in-memory database against the same list with a few skins rotated, so the
+// skin tracker sees refreshes, changes and first sightings like it does live. the generated list is
+// seeded, the same size always gives the same file.
+
+pub const USAGE: &str =
+    "Usage: ddtracker bench <servers.json|YYYY-MM-DD.jsonl.zst> [--ticks <count>] [options]
+       ddtracker bench [--clients <count>] [--ticks <count>] [options]
+       ddtracker bench --generate <servers.json> [--clients <count>]";
+
+const REGIONS: &[&str] = &[
+    "as:cn", "as:kr", "as:jp", "as:sg", "eu:de", "eu:fr", "eu:pl", "eu:ru", "na:us", "sa:br",
+    "oc:au", "af:za",
+];
+const SKINS: &[&str] = &[
+    "default",
+    "santa_default",
+    "bluekitty",
+    "greensward",
+    "pinky",
+    "twinbop",
+    "cammo",
+    "coala",
+    "brownbear",
+    "redstripe",
+    "saddo",
+    "toptri",
+    "x_ninja",
+    "limekitty",
+    "nanami",
+    "mouse",
+];
+const MAPS: &[&str] = &[
+    "Tutorial",
+    "Multeasymap",
+    "Sunny Side Up",
+    "Kobra 4",
+    "Stronghold",
+    "Back in the Days",
+    "Grandma",
+    "Baby Aim 1.0",
+    "Linear",
+    "Epix",
+];
+const GAME_TYPES: &[&str] = &["DDraceNetwork", "Gores", "Block", "fng2", "CTF"];
+
+/// Small seeded generator, the fixture only has to be varied and the same on every run
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[self.below(items.len())]
+    }
+}
+
+fn random_skin(rng: &mut Rng) -> Skin {
+    // about half the players use custom colors
+    let colors = rng.below(2) == 0;
+    Skin {
+        name: Some(rng.pick(SKINS).to_string()),
+        color_body: colors.then(|| (rng.next() & 0xffffff) as i64),
+        color_feet: colors.then(|| (rng.next() & 0xffffff) as i64),
+    }
+}
+
+/// Puts about 5% of the players in another skin, like a tick of players changing skins
+pub(crate) fn rotate_skins(list: &mut ServerList, rng: &mut Rng) {
+    for server in &mut list.servers {
+        for client in &mut server.info.clients {
+            if rng.below(20) == 0 {
+                client.skin = Some(random_skin(rng));
+            }
+        }
+    }
+}
+
+/// Writes a servers.json with about `clients` clients spread over full and half full servers
+pub fn generate(path: &str, clients: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let list = fixture(clients);
+    std::fs::write(path, serde_json::to_string(&list)?)
+        .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    println!(
+        "Wrote {} clients on {} servers to {}",
+        clients,
+        list.servers.len(),
+        path
+    );
+    Ok(())
+}
+
+/// The generated list, seeded so the same size always gives the same servers
+pub(crate) fn fixture(clients: usize) -> ServerList {
+    let mut rng = Rng::new(0x5eed_dd7e_ac4e_1234);
+    // a small share of names shows up more than once, like common names and dummies do
+    let names = clients - clients / 20;
+    let clans = (names / 8).max(1);
+    let mut list = ServerList::default();
+    let mut remaining = clients;
+    let mut index = 0;
+    while remaining > 0 {
+        let max_clients = if rng.below(4) == 0 { 16 } else { 64 };
+        let count = (max_clients / 2 + rng.below(max_clients / 2 + 1)).min(remaining);
+        remaining -= count;
+        let server_clients = (0..count)
+            .map(|_| {
+                let clan = if rng.below(3) == 0 {
+                    String::new()
+                } else {
+                    format!("clan{}", rng.below(clans))
+                };
+                Client {
+                    name: format!("player{}", rng.below(names)),
+                    clan,
+                    country: rng.below(1000) as i64,
+                    score: rng.below(100_000) as i64,
+                    is_player: rng.below(10) != 0,
+                    skin: Some(random_skin(&mut rng)),
+                    afk: rng.below(20) == 0,
+                    team: rng.below(8) as i64,
+                }
+            })
+            .collect();
+        let map = rng.pick(MAPS);
+        list.servers.push(Server {
+            addresses: vec![format!(
+                "tw-0.6+udp://10.{}.{}.1:8303",
+                index / 256,
+                index % 256
+            )],
+            location: Some(rng.pick(REGIONS).to_string()),
+            info: ServerInfo {
+                max_clients: max_clients as i64,
+                max_players: max_clients as i64,
+                passworded: false,
+                game_type: rng.pick(GAME_TYPES).to_string(),
+                name: format!("Bench server #{}", index),
+                map: MapInfo {
+                    name: map.to_string(),
+                    sha256: Some(format!("{:064x}", map.len())),
+                    size: Some(1000 + map.len() as i64),
+                },
+                version: "0.6.4, 18.0".to_string(),
+                clients: server_clients,
+            },
+        });
+        index += 1;
+    }
+    list
+}
+
+/// Reads a recorded list, the busiest tick when it is a daily file of the archive
+pub fn load(path: &str) -> Result<ServerList, Box<dyn std::error::Error>> {
+    let (list, issues) = if path.ends_with(archive::EXTENSION) {
+        let mut busiest: Option<(i64, ServerList, Vec<model::ParseIssue>)> = None;
+        for record in
+            archive::read(Path::new(path)).map_err(|e| format!("Failed to read {}: {}", path, e))?
+        {
+            let record = record.map_err(|e| format!("Failed to read {}: {}", path, e))?;
+            let (list, issues) = model::parse(record.body.get())?;
+            if busiest
+                .as_ref()
+                .is_none_or(|(_, busiest, _)| clients(&list) > clients(busiest))
+            {
+                busiest = Some((record.time, list, issues));
+            }
+        }
+        let (time, list, issues) = busiest.ok_or_else(|| format!("No ticks in {}", path))?;
+        println!("Using the busiest tick of {}, minute {}", path, time);
+        (list, issues)
+    } else {
+        let body =
+            std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        model::parse(&body)?
+    };
+    let malformed = issues.iter().filter(|issue| issue.client.is_none()).count();
+    println!(
+        "{} clients on {} servers, {} malformed servers and {} malformed clients skipped",
+        clients(&list),
+        list.servers.len(),
+        malformed,
+        issues.len() - malformed
+    );
+    Ok(list)
+}
+
+fn clients(list: &ServerList) -> usize {
+    list.servers.iter().map(|s| s.info.clients.len()).sum()
+}
+
+/// Runs every tracker over the list `ticks` times and prints how long each tick took
+pub fn run(
+    mut list: ServerList,
+    ticks: usize,
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut conn = Connection::open_in_memory()?;
+    conn.set_prepared_statement_cache_capacity(ingest::STATEMENT_CACHE_CAPACITY);
+    migrations::run(&mut conn, false)?;
+
+    let mut rng = Rng::new(0x0b5e_55ed);
+    let start = chrono::Utc::now().timestamp() / 60;
+    let mut times = Vec::with_capacity(ticks);
+    for tick in 0..ticks {
+        if tick > 0 {
+            rotate_skins(&mut list, &mut rng);
+        }
+
+        let started = Instant::now();
+        let tx = conn.transaction()?;
+        ingest::run(&tx, &list, start + tick as i64, 1, config, &Tracker::ALL)?;
+        tx.commit()?;
+        let elapsed = started.elapsed().as_secs_f64() * 1000.0;
+        println!("Tick {:>3}: {:>8.1} ms", tick + 1, elapsed);
+        times.push(elapsed);
+    }
+
+    if !times.is_empty() {
+        let mean = times.iter().sum::<f64>() / times.len() as f64;
+        let max = times.iter().cloned().fold(0.0, f64::max);
+        println!(
+            "Mean {:.1} ms, max {:.1} ms over {} ticks",
+            mean, max, ticks
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+    use crate::fetch::Fetched;
+
+    #[test]
+    fn archive_days_bench_their_busiest_tick() {
+        let dir = std::env::temp_dir().join(format!("ddtracker-bench-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let archive = Archive::new(dir.to_str().unwrap()).unwrap();
+        // 2025-01-06, quiet at night, busy in the evening
+        let day = 20_094 * 24 * 60;
+        for (time, count) in [(day + 60, 1), (day + 20 * 60, 3), (day + 23 * 60, 2)] {
+            let clients: Vec<String> = (0..count)
+                .map(|index| format!(r#"{{"name":"Tee{}"}}"#, index))
+                .collect();
+            let body = format!(
+                r#"{{"servers":[{{"addresses":["tw-0.6+udp://1.1.1.1:8303"],"location":"eu:de","info":{{"clients":[{}]}}}}]}}"#,
+                clients.join(",")
+            );
+            let fetched = Fetched {
+                servers: model::parse(&body).unwrap().0,
+                malformed: 0,
+                sources: Vec::new(),
+                raw: Some(body),
+            };
+            archive.store(time, &fetched).unwrap();
+        }
+
+        let path = dir.join("2025-01-06.jsonl.zst");
+        let list = load(path.to_str().unwrap()).unwrap();
+        assert_eq!(clients(&list), 3);
+        run(list, 2, &Config::default()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
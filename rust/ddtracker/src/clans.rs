@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+
+use log::info;
+use rusqlite::{params, Transaction};
+
+use crate::model::ServerList;
+
+// clan tags are stored exactly as the master reports them, no trimming or case folding.
+// `clan_members` is the full history, `player_clans` is the clan a player is currently wearing
+// (empty when they were last seen without one).
+
+pub fn update(tx: &Transaction, servers_data: &ServerList, now: i64) -> rusqlite::Result<()> {
+    let mut players: HashMap<&str, &str> = HashMap::new();
+
+    for server in &servers_data.servers {
+        for client in &server.info.clients {
+            players.insert(client.name.as_str(), client.clan.as_str());
+        }
+    }
+
+    let mut member_stmt = tx.prepare_cached(
+        "INSERT INTO clan_members (clan, name, first_seen, last_seen) VALUES (?1, ?2, ?3, ?3)
+        ON CONFLICT (clan, name) DO UPDATE SET last_seen = excluded.last_seen",
+    )?;
+    let mut current_stmt = tx.prepare_cached(
+        "INSERT INTO player_clans (name, clan, since, last_seen) VALUES (?1, ?2, ?3, ?3)
+        ON CONFLICT (name) DO UPDATE SET
+            since = CASE WHEN clan = excluded.clan THEN since ELSE excluded.since END,
+            clan = excluded.clan,
+            last_seen = excluded.last_seen",
+    )?;
+
+    let mut members = 0;
+    for (name, clan) in &players {
+        if !clan.is_empty() {
+            member_stmt.execute(params![clan, name, now])?;
+            members += 1;
+        }
+        current_stmt.execute(params![name, clan, now])?;
+    }
+
+    info!(
+        "Clans: {} players seen, {} in a clan",
+        players.len(),
+        members
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::{migrations, model};
+
+    fn tick(conn: &mut Connection, clan: &str, now: i64) {
+        let (servers, _) = model::parse(&format!(
+            r#"{{"servers":[{{"addresses":["tw-0.6+udp://1.1.1.1:8303"],"location":"eu:de","info":{{"clients":[{{"name":"Tee","clan":"{}"}}]}}}}]}}"#,
+            clan
+        ))
+        .unwrap();
+        let tx = conn.transaction().unwrap();
+        update(&tx, &servers, now).unwrap();
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn clan_changes_keep_the_history() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn, false).unwrap();
+
+        tick(&mut conn, "Clan", 100);
+        tick(&mut conn, "Clan", 110);
+        tick(&mut conn, " clan", 120);
+        tick(&mut conn, "", 130);
+
+        // tags are kept as reported, a different case or space is another clan
+        let members: Vec<(String, i64, i64)> = conn
+            .prepare("SELECT clan, first_seen, last_seen FROM clan_members WHERE name = 'Tee' ORDER BY first_seen")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            members,
+            vec![
+                ("Clan".to_string(), 100, 110),
+                (" clan".to_string(), 120, 120)
+            ]
+        );
+
+        let current = |conn: &Connection| -> (String, i64, i64) {
+            conn.query_row(
+                "SELECT clan, since, last_seen FROM player_clans WHERE name = 'Tee'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap()
+        };
+        assert_eq!(current(&conn), (String::new(), 130, 130));
+        tick(&mut conn, "Clan", 140);
+        tick(&mut conn, "Clan", 150);
+        assert_eq!(current(&conn), ("Clan".to_string(), 140, 150));
+    }
+}
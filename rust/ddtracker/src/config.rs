@@ -0,0 +1,372 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::fetch::{FetchMode, Source};
+
+// settings are layered: built-in defaults, then the config file, then environment variables, then
+// command line flags. the config file is `--config`, `DDTRACKER_CONFIG` or `./ddtracker.toml` if it
+// exists.
+
+const DEFAULT_CONFIG_PATH: &str = "./ddtracker.toml";
+
+const USAGE: &str = "Usage: ddtracker [options]
+       ddtracker watch <add|list|remove> ... [options]
+       ddtracker alias <list|show|confirm|reject> ... [options]
+       ddtracker replay <archive-dir> [--only <tracker,...>] [--reset] [options]
+       ddtracker migrate [--status] [options]
+       ddtracker bench <servers.json|YYYY-MM-DD.jsonl.zst> [--ticks <count>] [options]
+       ddtracker bench [--clients <count>] [--ticks <count>] [options]
+       ddtracker bench --generate <servers.json> [--clients <count>]
+
+Options:
+  --config <path>                    config file (env DDTRACKER_CONFIG, default ./ddtracker.toml)
+  --servers-url <url>                master server list, repeat for more masters
+                                     (env DDTRACKER_SERVERS_URL, comma separated)
+  --fetch-mode <mode>                failover or parallel (env DDTRACKER_FETCH_MODE)
+  --source <source>                  master, udp or hybrid (env DDTRACKER_SOURCE)
+  --udp-server <address>             game server to poll directly, repeat for more servers
+                                     (env DDTRACKER_UDP_SERVERS, comma separated)
+  --udp-region <region>              region of udp servers the masters don't list (env DDTRACKER_UDP_REGION)
+  --udp-regions <region,...>         regions of master listed servers polled in hybrid mode, all when empty
+                                     (env DDTRACKER_UDP_REGIONS)
+  --udp-timeout <milliseconds>       time game servers have to answer (env DDTRACKER_UDP_TIMEOUT)
+  --cron <expression>                schedule, with seconds (env DDTRACKER_CRON)
+  --db <path>                        database file (env DDTRACKER_PATH)
+  --skin-change-delay <minutes>      minutes a skin has to be unseen before a change is accepted
+                                     (env DDTRACKER_SKIN_CHANGE_DELAY)
+  --population-retention-days <days> raw population rows to keep (env DDTRACKER_POPULATION_RETENTION_DAYS)
+  --http-timeout <seconds>           timeout of a single master request (env DDTRACKER_HTTP_TIMEOUT)
+  --retries <count>                  retries of a failed fetch or a busy database (env DDTRACKER_RETRIES)
+  --retry-backoff <milliseconds>     wait before the first retry, doubled every retry
+                                     (env DDTRACKER_RETRY_BACKOFF)
+  --db-busy-timeout <milliseconds>   wait for a locked database (env DDTRACKER_DB_BUSY_TIMEOUT)
+  --api-listen <address>             serve the query api, like 127.0.0.1:8400 (env DDTRACKER_API_LISTEN)
+  --archive-dir <path>               keep every fetched list in daily zstd files (env DDTRACKER_ARCHIVE_DIR)
+  --events-file <path>               append change events to a jsonl file (env DDTRACKER_EVENTS_FILE)
+  --watch-debounce <ticks>           ticks a watched player's new presence has to hold
+                                     (env DDTRACKER_WATCH_DEBOUNCE)
+  --watch-cooldown <minutes>         default quiet time of a watcher after notifying
+                                     (env DDTRACKER_WATCH_COOLDOWN)
+  --once                             run a single tick right away and exit
+  --dry-run                          fetch and process, but roll back instead of writing
+  --help                             show this message";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub servers_urls: Vec<String>,
+    pub fetch_mode: FetchMode,
+    /// Where server info comes from, the masters, the game servers or both
+    pub source: Source,
+    /// Game servers that are always polled in udp and hybrid mode
+    pub udp_servers: Vec<String>,
+    /// Region recorded for `udp_servers` the masters don't list
+    pub udp_region: Option<String>,
+    /// Regions of master listed servers polled in hybrid mode, all when empty. `as` covers `as:cn`.
+    pub udp_regions: Vec<String>,
+    /// Milliseconds to wait for game servers to answer
+    pub udp_timeout: u64,
+    pub cron: String,
+    pub database: String,
+    pub skin_change_delay: i64,
+    pub population_retention_days: i64,
+    /// Seconds
+    pub http_timeout: u64,
+    pub retries: u32,
+    /// Milliseconds before the first retry, doubled on every following one
+    pub retry_backoff: u64,
+    /// Milliseconds
+    pub db_busy_timeout: u64,
+    /// Address of the query api, disabled when not set
+    pub api_listen: Option<String>,
+    /// Directory of the daily snapshot files, disabled when not set
+    pub archive_dir: Option<String>,
+    /// JSONL file change events are appended to, disabled when not set
+    pub events_file: Option<String>,
+    /// Ticks in a row a watched player's new presence has to be seen before it's announced
+    pub watch_debounce: u32,
+    /// Default minutes a watcher stays quiet about a player after notifying about them
+    pub watch_cooldown: i64,
+
+    #[serde(skip)]
+    pub once: bool,
+    #[serde(skip)]
+    pub dry_run: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            servers_urls: (1..=4)
+                .map(|i| format!("https://master{}.ddnet.org/ddnet/15/servers.json", i))
+                .collect(),
+            fetch_mode: FetchMode::Failover,
+            source: Source::Master,
+            udp_servers: Vec::new(),
+            udp_region: None,
+            udp_regions: Vec::new(),
+            udp_timeout: 2000,
+            cron: "0 * * * * *".to_string(),
+            database: "./cache/ddtracker.db".to_string(),
+            skin_change_delay: 5,
+            population_retention_days: 7,
+            http_timeout: 20,
+            retries: 2,
+            retry_backoff: 2000,
+            db_busy_timeout: 5000,
+            api_listen: None,
+            archive_dir: None,
+            events_file: None,
+            watch_debounce: 2,
+            watch_cooldown: 10,
+            once: false,
+            dry_run: false,
+        }
+    }
+}
+
+impl Config {
+    /// Raw population retention window in minutes
+    pub fn population_retention(&self) -> i64 {
+        self.population_retention_days * 24 * 60
+    }
+
+    /// Builds the config from the file, environment and the given command line arguments
+    /// (without the program name). Returns `None` when only the usage was requested.
+    pub fn load(args: &[String]) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let mut config_path = std::env::var("DDTRACKER_CONFIG").ok();
+        let mut args_iter = args.iter();
+        while let Some(arg) = args_iter.next() {
+            if arg == "--config" {
+                config_path = Some(value_of(arg, args_iter.next())?.to_string());
+            }
+        }
+
+        let mut config = match config_path {
+            Some(path) => Self::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(DEFAULT_CONFIG_PATH)?
+            }
+            None => Self::default(),
+        };
+
+        config.apply_env()?;
+
+        let mut servers_urls = Vec::new();
+        let mut udp_servers = Vec::new();
+        let mut args_iter = args.iter();
+        while let Some(arg) = args_iter.next() {
+            match arg.as_str() {
+                "--config" => {
+                    args_iter.next();
+                }
+                "--servers-url" => servers_urls.push(value_of(arg, args_iter.next())?.to_string()),
+                "--fetch-mode" => config.fetch_mode = value_of(arg, args_iter.next())?.parse()?,
+                "--source" => config.source = value_of(arg, args_iter.next())?.parse()?,
+                "--udp-server" => udp_servers.push(value_of(arg, args_iter.next())?.to_string()),
+                "--udp-region" => {
+                    config.udp_region = Some(value_of(arg, args_iter.next())?.to_string())
+                }
+                "--udp-regions" => {
+                    config.udp_regions = split_list(value_of(arg, args_iter.next())?)
+                }
+                "--udp-timeout" => {
+                    config.udp_timeout = parse_number(arg, value_of(arg, args_iter.next())?)?
+                }
+                "--cron" => config.cron = value_of(arg, args_iter.next())?.into(),
+                "--db" => config.database = value_of(arg, args_iter.next())?.into(),
+                "--skin-change-delay" => {
+                    config.skin_change_delay = parse_number(arg, value_of(arg, args_iter.next())?)?
+                }
+                "--population-retention-days" => {
+                    config.population_retention_days =
+                        parse_number(arg, value_of(arg, args_iter.next())?)?
+                }
+                "--http-timeout" => {
+                    config.http_timeout = parse_number(arg, value_of(arg, args_iter.next())?)?
+                }
+                "--retries" => {
+                    config.retries = parse_number(arg, value_of(arg, args_iter.next())?)?
+                }
+                "--retry-backoff" => {
+                    config.retry_backoff = parse_number(arg, value_of(arg, args_iter.next())?)?
+                }
+                "--db-busy-timeout" => {
+                    config.db_busy_timeout = parse_number(arg, value_of(arg, args_iter.next())?)?
+                }
+                "--api-listen" => {
+                    config.api_listen = Some(value_of(arg, args_iter.next())?.to_string())
+                }
+                "--archive-dir" => {
+                    config.archive_dir = Some(value_of(arg, args_iter.next())?.to_string())
+                }
+                "--events-file" => {
+                    config.events_file = Some(value_of(arg, args_iter.next())?.to_string())
+                }
+                "--watch-debounce" => {
+                    config.watch_debounce = parse_number(arg, value_of(arg, args_iter.next())?)?
+                }
+                "--watch-cooldown" => {
+                    config.watch_cooldown = parse_number(arg, value_of(arg, args_iter.next())?)?
+                }
+                "--once" => config.once = true,
+                "--dry-run" => config.dry_run = true,
+                "--help" | "-h" => {
+                    println!("{}", USAGE);
+                    return Ok(None);
+                }
+                _ => return Err(format!("Unknown argument {}, see --help", arg).into()),
+            }
+        }
+
+        if !servers_urls.is_empty() {
+            config.servers_urls = servers_urls;
+        }
+        if !udp_servers.is_empty() {
+            config.udp_servers = udp_servers;
+        }
+        if config.servers_urls.is_empty() && config.source != Source::Udp {
+            return Err("At least one servers url is required".into());
+        }
+        if config.udp_servers.is_empty() && config.source == Source::Udp {
+            return Err("At least one udp server is required in udp mode".into());
+        }
+
+        Ok(Some(config))
+    }
+
+    fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path, e))?;
+        let config = toml::from_str(&content)
+            .map_err(|e| format!("Failed to parse config file {}: {}", path, e))?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Ok(value) = std::env::var("DDTRACKER_SERVERS_URL") {
+            self.servers_urls = split_list(&value);
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_FETCH_MODE") {
+            self.fetch_mode = value.parse()?;
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_SOURCE") {
+            self.source = value.parse()?;
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_UDP_SERVERS") {
+            self.udp_servers = split_list(&value);
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_UDP_REGION") {
+            self.udp_region = Some(value).filter(|value| !value.is_empty());
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_UDP_REGIONS") {
+            self.udp_regions = split_list(&value);
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_UDP_TIMEOUT") {
+            self.udp_timeout = parse_number("DDTRACKER_UDP_TIMEOUT", &value)?;
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_CRON") {
+            self.cron = value;
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_PATH") {
+            self.database = value;
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_SKIN_CHANGE_DELAY") {
+            self.skin_change_delay = parse_number("DDTRACKER_SKIN_CHANGE_DELAY", &value)?;
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_POPULATION_RETENTION_DAYS") {
+            self.population_retention_days =
+                parse_number("DDTRACKER_POPULATION_RETENTION_DAYS", &value)?;
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_HTTP_TIMEOUT") {
+            self.http_timeout = parse_number("DDTRACKER_HTTP_TIMEOUT", &value)?;
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_API_LISTEN") {
+            self.api_listen = Some(value).filter(|value| !value.is_empty());
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_ARCHIVE_DIR") {
+            self.archive_dir = Some(value).filter(|value| !value.is_empty());
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_EVENTS_FILE") {
+            self.events_file = Some(value).filter(|value| !value.is_empty());
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_RETRIES") {
+            self.retries = parse_number("DDTRACKER_RETRIES", &value)?;
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_RETRY_BACKOFF") {
+            self.retry_backoff = parse_number("DDTRACKER_RETRY_BACKOFF", &value)?;
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_DB_BUSY_TIMEOUT") {
+            self.db_busy_timeout = parse_number("DDTRACKER_DB_BUSY_TIMEOUT", &value)?;
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_WATCH_DEBOUNCE") {
+            self.watch_debounce = parse_number("DDTRACKER_WATCH_DEBOUNCE", &value)?;
+        }
+        if let Ok(value) = std::env::var("DDTRACKER_WATCH_COOLDOWN") {
+            self.watch_cooldown = parse_number("DDTRACKER_WATCH_COOLDOWN", &value)?;
+        }
+        Ok(())
+    }
+}
+
+fn value_of<'a>(name: &str, value: Option<&'a String>) -> Result<&'a str, String> {
+    value
+        .map(String::as_str)
+        .ok_or_else(|| format!("Missing value for {}", name))
+}
+
+/// Comma separated values with the blanks around them trimmed and empty ones left out
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid number for {}: {}", name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(args: &[&str]) -> Config {
+        let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        args.extend(["--config".to_string(), "/dev/null".to_string()]);
+        Config::load(&args).unwrap().unwrap()
+    }
+
+    #[test]
+    fn flags_override_every_setting() {
+        let config = load(&[
+            "--udp-region",
+            "eu:de",
+            "--udp-regions",
+            "as, eu:de,",
+            "--udp-timeout",
+            "500",
+            "--retry-backoff",
+            "10",
+            "--db-busy-timeout",
+            "100",
+            "--watch-debounce",
+            "3",
+            "--watch-cooldown",
+            "30",
+        ]);
+        assert_eq!(config.udp_region.as_deref(), Some("eu:de"));
+        assert_eq!(config.udp_regions, ["as", "eu:de"]);
+        assert_eq!(config.udp_timeout, 500);
+        assert_eq!(config.retry_backoff, 10);
+        assert_eq!(config.db_busy_timeout, 100);
+        assert_eq!(config.watch_debounce, 3);
+        assert_eq!(config.watch_cooldown, 30);
+
+        assert!(Config::load(&["--watch-debounce".to_string(), "soon".to_string()]).is_err());
+    }
+}
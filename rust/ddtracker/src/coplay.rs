@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+
+use log::info;
+use rusqlite::{params, Transaction};
+
+use crate::{ingest, model::ServerList};
+
+// clients on the same server in the same tick have played together for the minutes since the last
+// tick, clients in the same ddrace team (anything but team 0) count `TEAM_BONUS` times that more. on
+// servers with more than
+// `CROWD` clients only teammates count, a full block server hasn't all played together. afk clients
+// don't count. edges are stored once per pair, names ordered so `a < b`. faded edges are pruned on
+// the first tick of each day.
+//
+// weights decay with a half-life of `HALF_LIFE` minutes. instead of decaying every edge each tick, a
+// minute at time t adds 2^((t - EPOCH) / HALF_LIFE), so stored weights only grow and still rank the
+// same as decayed ones. divided by 2^((now - EPOCH) / HALF_LIFE) they are minutes as of now. the
+// website divides by the same constants, changing them means rebuilding the table with a replay.
+
+/// Start of the weight scale, 2025-01-01 in minutes since the epoch
+const EPOCH: i64 = 28_928_160;
+/// 30 days
+const HALF_LIFE: i64 = 30 * 24 * 60;
+const TEAM_BONUS: f64 = 2.0;
+const CROWD: usize = 16;
+/// Edges that decayed below this many minutes are dropped once a day
+const MIN_WEIGHT: f64 = 1.0;
+
+fn scale(time: i64) -> f64 {
+    2f64.powf((time - EPOCH) as f64 / HALF_LIFE as f64)
+}
+
+pub fn update(
+    tx: &Transaction,
+    servers_data: &ServerList,
+    now: i64,
+    minutes: i64,
+) -> rusqlite::Result<()> {
+    // weight and minutes of every pair this tick
+    let mut pairs: HashMap<(&str, &str), (f64, i64)> = HashMap::new();
+    for server in &servers_data.servers {
+        let mut clients: Vec<(&str, i64)> = server
+            .info
+            .clients
+            .iter()
+            .filter(|client| !client.afk)
+            .map(|client| (client.name.as_str(), client.team))
+            .collect();
+        // same name twice on one server, the first one covers it
+        clients.sort_unstable();
+        clients.dedup_by(|a, b| a.0 == b.0);
+        let crowded = clients.len() > CROWD;
+
+        for (index, &(a, team_a)) in clients.iter().enumerate() {
+            for &(b, team_b) in &clients[index + 1..] {
+                let teammates = team_a != 0 && team_a == team_b;
+                if crowded && !teammates {
+                    continue;
+                }
+                let weight = if teammates { 1.0 + TEAM_BONUS } else { 1.0 };
+                // a pair on two servers at once (same names) adds both weights but its minutes once
+                let pair = pairs.entry((a, b)).or_default();
+                pair.0 += weight * minutes as f64;
+                pair.1 = minutes;
+            }
+        }
+    }
+
+    let factor = scale(now);
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO coplay (a, b, weight, minutes, last_seen) VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT (a, b) DO UPDATE SET weight = weight + excluded.weight, minutes = minutes + excluded.minutes, last_seen = excluded.last_seen",
+    )?;
+    for ((a, b), (weight, minutes)) in &pairs {
+        stmt.execute(params![a, b, weight * factor, minutes, now])?;
+    }
+
+    if ingest::first_tick_of_day(tx, "coplay_pruned", now)? {
+        let pruned = tx
+            .prepare_cached("DELETE FROM coplay WHERE weight < ?")?
+            .execute(params![MIN_WEIGHT * factor])?;
+        info!("Co-play: dropped {} faded edges", pruned);
+    }
+
+    info!("Co-play: {} pairs played together", pairs.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::{migrations, model};
+
+    fn edges(conn: &Connection) -> Vec<(String, String, i64)> {
+        conn.prepare("SELECT a, b, minutes FROM coplay ORDER BY a, b")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn pairs_add_tick_minutes_and_prune_after_a_missed_midnight() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn, false).unwrap();
+        let (servers, _) = model::parse(
+            r#"{"servers":[
+                {"addresses":["tw-0.6+udp://1.1.1.1:8303"],"location":"eu:de","info":{"clients":[
+                    {"name":"A","team":3},{"name":"B","team":3},{"name":"C"},{"name":"D","afk":true}
+                ]}}
+            ]}"#,
+        )
+        .unwrap();
+
+        // 10:00 on the weight epoch, then five minutes later on a five minute schedule
+        let start = EPOCH + 10 * 60;
+        let tx = conn.transaction().unwrap();
+        update(&tx, &servers, start, 1).unwrap();
+        update(&tx, &servers, start + 5, 5).unwrap();
+        tx.commit().unwrap();
+
+        // D is afk, every other pair played six minutes together
+        let played: Vec<(String, String, i64)> = [("A", "B"), ("A", "C"), ("B", "C")]
+            .into_iter()
+            .map(|(a, b)| (a.to_string(), b.to_string(), 6))
+            .collect();
+        assert_eq!(edges(&conn), played);
+        // teammates weigh `1 + TEAM_BONUS` times as much
+        let weights: Vec<f64> = conn
+            .prepare("SELECT weight FROM coplay ORDER BY a, b")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let expected = scale(start) + 5.0 * scale(start + 5);
+        assert!((weights[0] - (1.0 + TEAM_BONUS) * expected).abs() < 1e-9);
+        assert!((weights[1] - expected).abs() < 1e-9);
+
+        // no tick at midnight, the first one of the next day still prunes the faded edge
+        conn.execute(
+            "INSERT INTO coplay (a, b, weight, minutes, last_seen) VALUES ('X', 'Y', 0.001, 1, ?)",
+            [start],
+        )
+        .unwrap();
+        let next_day = start + 14 * 60 + 3;
+        let tx = conn.transaction().unwrap();
+        update(&tx, &servers, next_day, 5).unwrap();
+        tx.commit().unwrap();
+        assert!(edges(&conn).iter().all(|(a, _, _)| a != "X"));
+
+        // and later ticks of the same day leave edges alone
+        conn.execute(
+            "INSERT INTO coplay (a, b, weight, minutes, last_seen) VALUES ('X', 'Y', 0.001, 1, ?)",
+            [next_day],
+        )
+        .unwrap();
+        let tx = conn.transaction().unwrap();
+        update(&tx, &servers, next_day + 5, 5).unwrap();
+        tx.commit().unwrap();
+        assert!(edges(&conn).iter().any(|(a, _, _)| a == "X"));
+    }
+}
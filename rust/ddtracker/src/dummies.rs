@@ -0,0 +1,375 @@
+use std::collections::{HashMap, HashSet};
+
+use log::info;
+use rusqlite::{params, Transaction};
+
+use crate::ingest;
+use crate::model::{Client, ServerList};
+
+// ddnet players connect a dummy from the same client, it joins right after them with the same clan,
+// country and usually the same skin. ticks are a minute apart, so two clients that first show up on
+// a server in the same tick with all three matching are a candidate pair. every such join raises the
+// pair's confidence, names that look alike raise it more, and every tick both are online on different
+// servers lowers it. the name contained in the other, or else the one listed first, is the owner.
+// `dummy_clients` and `dummy_servers` hold last tick's clients and servers to tell who joined. once
+// a day links that faded away or weren't seen together for a while are dropped.
+
+/// Links this confident count as owner and dummy for population, play time, activity and skin
+/// statistics
+pub const DUMMY_CONFIDENCE: f64 = 0.75;
+/// Confidence a single join adds, for pairs with unrelated and with similar names
+const JOIN_EVIDENCE: f64 = 0.3;
+const SIMILAR_JOIN_EVIDENCE: f64 = 0.6;
+/// Factor applied for every tick the pair is seen apart
+const APART_PENALTY: f64 = 0.8;
+/// More joins than this on one server in one tick is a reconnect wave, not a dummy
+const MAX_JOINS: usize = 4;
+/// Minutes since last tick after which nobody can be said to have just joined
+const MAX_GAP: i64 = 5;
+/// Names sharing a prefix this long are similar
+const SIMILAR_PREFIX: usize = 3;
+/// Links below this confidence are dropped, a coincidence seen apart a few times
+const MIN_CONFIDENCE: f64 = 0.05;
+/// Links whose names weren't together for this many days are dropped
+const STALE_DAYS: i64 = 30;
+
+/// Likely dummies by server address and name
+pub type Dummies<'a> = HashSet<(&'a str, &'a str)>;
+
+fn similar(a: &str, b: &str) -> bool {
+    let (a, b) = (a.to_lowercase(), b.to_lowercase());
+    if a.contains(&b) || b.contains(&a) {
+        return true;
+    }
+    a.chars().zip(b.chars()).take_while(|(x, y)| x == y).count() >= SIMILAR_PREFIX
+}
+
+fn same_setup(a: &Client, b: &Client) -> bool {
+    let skin = |client: &Client| {
+        client
+            .skin
+            .as_ref()
+            .map(|skin| (skin.name.clone(), skin.color_body, skin.color_feet))
+    };
+    a.clan == b.clan && a.country == b.country && skin(a) == skin(b)
+}
+
+/// Clients in the list that are the likely dummy of another client on the same server, by address
+/// and name
+pub fn likely_dummies<'a>(
+    tx: &Transaction,
+    servers_data: &'a ServerList,
+) -> rusqlite::Result<Dummies<'a>> {
+    let mut owners: HashMap<String, Vec<String>> = HashMap::new();
+    {
+        let mut stmt =
+            tx.prepare_cached("SELECT dummy, owner FROM dummy_links WHERE confidence >= ?")?;
+        let mut rows = stmt.query(params![DUMMY_CONFIDENCE])?;
+        while let Some(row) = rows.next()? {
+            owners.entry(row.get(0)?).or_default().push(row.get(1)?);
+        }
+    }
+
+    let mut dummies = HashSet::new();
+    if owners.is_empty() {
+        return Ok(dummies);
+    }
+    for server in &servers_data.servers {
+        let Some(address) = server.address() else {
+            continue;
+        };
+        let names: HashSet<&str> = server
+            .info
+            .clients
+            .iter()
+            .map(|client| client.name.as_str())
+            .collect();
+        for name in &names {
+            if let Some(candidates) = owners.get(*name) {
+                if candidates
+                    .iter()
+                    .any(|owner| names.contains(owner.as_str()))
+                {
+                    dummies.insert((address, *name));
+                }
+            }
+        }
+    }
+    Ok(dummies)
+}
+
+pub fn update(tx: &Transaction, servers_data: &ServerList, now: i64) -> rusqlite::Result<()> {
+    let last_tick: Option<i64> =
+        tx.query_row("SELECT MAX(time) FROM dummy_servers", [], |row| row.get(0))?;
+    let mut previous: HashMap<String, HashSet<String>> = HashMap::new();
+    if last_tick.is_some_and(|last| now - last <= MAX_GAP) {
+        let mut stmt = tx.prepare_cached("SELECT address FROM dummy_servers")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            previous.insert(row.get(0)?, HashSet::new());
+        }
+        let mut stmt = tx.prepare_cached("SELECT address, name FROM dummy_clients")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            previous.entry(row.get(0)?).or_default().insert(row.get(1)?);
+        }
+    }
+
+    let mut pairs: Vec<(&str, &str, f64)> = Vec::new();
+    let mut seen: HashSet<&str> = HashSet::new();
+    for server in &servers_data.servers {
+        let Some(address) = server.address() else {
+            continue;
+        };
+        if !seen.insert(address) {
+            continue;
+        }
+        // a server that just appeared has everyone joining at once, one that was empty hasn't
+        let Some(before) = previous.get(address) else {
+            continue;
+        };
+        let joined: Vec<&Client> = server
+            .info
+            .clients
+            .iter()
+            .filter(|client| !before.contains(&client.name) && !client.is_anonymous())
+            .collect();
+        if joined.len() > MAX_JOINS {
+            continue;
+        }
+        for (index, &a) in joined.iter().enumerate() {
+            for &b in &joined[index + 1..] {
+                if a.name == b.name || !same_setup(a, b) {
+                    continue;
+                }
+                let (owner, dummy) = if b.name.to_lowercase().contains(&a.name.to_lowercase()) {
+                    (a, b)
+                } else if a.name.to_lowercase().contains(&b.name.to_lowercase()) {
+                    (b, a)
+                } else {
+                    (a, b)
+                };
+                let evidence = if similar(&a.name, &b.name) {
+                    SIMILAR_JOIN_EVIDENCE
+                } else {
+                    JOIN_EVIDENCE
+                };
+                pairs.push((owner.name.as_str(), dummy.name.as_str(), evidence));
+            }
+        }
+    }
+
+    // a pair seen the other way round before keeps its first owner
+    let mut join_stmt = tx.prepare_cached(
+        "UPDATE dummy_links SET joins = joins + 1, confidence = 1 - (1 - confidence) * (1 - ?3), last_seen = ?4
+        WHERE (owner = ?1 AND dummy = ?2) OR (owner = ?2 AND dummy = ?1)",
+    )?;
+    let mut insert_stmt = tx.prepare_cached(
+        "INSERT INTO dummy_links (owner, dummy, joins, apart, confidence, first_seen, last_seen) VALUES (?1, ?2, 1, 0, ?3, ?4, ?4)",
+    )?;
+    let mut found = 0;
+    for (owner, dummy, evidence) in &pairs {
+        if join_stmt.execute(params![owner, dummy, evidence, now])? == 0 {
+            insert_stmt.execute(params![owner, dummy, evidence, now])?;
+            found += 1;
+        }
+    }
+
+    tx.prepare_cached("DELETE FROM dummy_clients")?
+        .execute([])?;
+    tx.prepare_cached("DELETE FROM dummy_servers")?
+        .execute([])?;
+    let mut client_stmt = tx.prepare_cached(
+        "INSERT OR IGNORE INTO dummy_clients (address, name, time) VALUES (?, ?, ?)",
+    )?;
+    let mut server_stmt =
+        tx.prepare_cached("INSERT OR IGNORE INTO dummy_servers (address, time) VALUES (?, ?)")?;
+    for server in &servers_data.servers {
+        let Some(address) = server.address() else {
+            continue;
+        };
+        server_stmt.execute(params![address, now])?;
+        for client in &server.info.clients {
+            client_stmt.execute(params![address, client.name, now])?;
+        }
+    }
+
+    // only links with both names online, found from the online names through the indexes. a dummy
+    // can't be on another server than its owner
+    let together = tx
+        .prepare_cached(
+            "UPDATE dummy_links SET last_seen = ?1 WHERE rowid IN (
+                SELECT l.rowid FROM dummy_clients o JOIN dummy_links l ON l.owner = o.name
+                JOIN dummy_clients d ON d.name = l.dummy AND d.address = o.address
+            )",
+        )?
+        .execute(params![now])?;
+    let apart = tx
+        .prepare_cached(
+            "UPDATE dummy_links SET apart = apart + 1, confidence = confidence * ?1 WHERE rowid IN (
+                SELECT l.rowid FROM dummy_clients o JOIN dummy_links l ON l.owner = o.name
+                JOIN dummy_clients d ON d.name = l.dummy
+                GROUP BY l.rowid HAVING MAX(d.address = o.address) = 0
+            )",
+        )?
+        .execute(params![APART_PENALTY])?;
+
+    if ingest::first_tick_of_day(tx, "dummies_pruned", now)? {
+        let pruned = tx
+            .prepare_cached("DELETE FROM dummy_links WHERE confidence < ? OR last_seen < ?")?
+            .execute(params![MIN_CONFIDENCE, now - STALE_DAYS * 24 * 60])?;
+        info!("Dummies: dropped {} faded or stale links", pruned);
+    }
+
+    info!(
+        "Dummies: {} joined as pairs, {} new links, {} links seen together, {} seen apart",
+        pairs.len(),
+        found,
+        together,
+        apart
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::{activity, maps, migrations, model};
+
+    fn list(servers: &[(&str, &[&str])]) -> ServerList {
+        let servers: Vec<String> = servers
+            .iter()
+            .map(|(address, names)| {
+                let clients: Vec<String> = names
+                    .iter()
+                    .map(|name| {
+                        format!(
+                            r#"{{"name":"{}","clan":"Dummies","country":276,"skin":{{"name":"pinky"}}}}"#,
+                            name
+                        )
+                    })
+                    .collect();
+                format!(
+                    r#"{{"addresses":["{}"],"info":{{"clients":[{}]}}}}"#,
+                    address,
+                    clients.join(",")
+                )
+            })
+            .collect();
+        model::parse(&format!(r#"{{"servers":[{}]}}"#, servers.join(",")))
+            .unwrap()
+            .0
+    }
+
+    fn links(conn: &Connection) -> Vec<(String, String, i64, f64, i64)> {
+        conn.prepare("SELECT owner, dummy, apart, round(confidence, 2), last_seen FROM dummy_links ORDER BY owner, dummy")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn pairs_joining_an_empty_server_are_linked_and_faded_links_pruned() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn, false).unwrap();
+        let (a, b) = ("tw-0.6+udp://1.1.1.1:8303", "tw-0.6+udp://2.2.2.2:8303");
+        let start = 28_928_160 + 1000;
+        let tick = |conn: &mut Connection, servers: &ServerList, now: i64| {
+            let tx = conn.transaction().unwrap();
+            update(&tx, servers, now).unwrap();
+            tx.commit().unwrap();
+        };
+
+        tick(&mut conn, &list(&[(a, &[]), (b, &["someone"])]), start);
+        tick(
+            &mut conn,
+            &list(&[(a, &["Tee", "Tee (d)"]), (b, &["someone"])]),
+            start + 1,
+        );
+        tick(&mut conn, &list(&[(a, &["Tee", "Tee (d)"])]), start + 2);
+        tick(
+            &mut conn,
+            &list(&[(a, &["Tee"]), (b, &["Tee (d)"])]),
+            start + 3,
+        );
+        assert_eq!(
+            links(&conn),
+            [("Tee".into(), "Tee (d)".into(), 1, 0.48, start + 2)]
+        );
+
+        let insert = "INSERT INTO dummy_links (owner, dummy, joins, apart, confidence, first_seen, last_seen) VALUES (?, ?, 1, 0, ?, ?, ?)";
+        conn.execute(insert, params!["faded", "faded (d)", 0.01, start, start])
+            .unwrap();
+        conn.execute(insert, params!["stale", "stale (d)", 0.9, 0, 0])
+            .unwrap();
+        // only the first tick of a day prunes, even when midnight itself was missed
+        tick(&mut conn, &list(&[]), start + 4);
+        assert_eq!(links(&conn).len(), 3);
+        tick(&mut conn, &list(&[]), 28_928_160 + 24 * 60 + 1);
+        assert_eq!(
+            links(&conn),
+            [("Tee".into(), "Tee (d)".into(), 1, 0.48, start + 2)]
+        );
+    }
+
+    #[test]
+    fn likely_dummies_stay_out_of_playtime_and_activity() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn, false).unwrap();
+        conn.execute(
+            "INSERT INTO dummy_links (owner, dummy, joins, apart, confidence, first_seen, last_seen) VALUES ('Tee', 'Tee (d)', 3, 0, 0.9, 0, 0)",
+            [],
+        )
+        .unwrap();
+        // the dummy only counts when its owner isn't on the same server
+        let (servers, _) = model::parse(
+            r#"{"servers":[
+                {"addresses":["tw-0.6+udp://1.1.1.1:8303"],"location":"eu:de","info":{"map":{"name":"Kobra"},"clients":[
+                    {"name":"Tee","is_player":true},{"name":"Tee (d)","is_player":true},{"name":"other","is_player":true}]}},
+                {"addresses":["tw-0.6+udp://2.2.2.2:8303"],"location":"as:cn","info":{"map":{"name":"Kobra"},"clients":[
+                    {"name":"Tee (d)","is_player":true}]}}
+            ]}"#,
+        )
+        .unwrap();
+
+        let tx = conn.transaction().unwrap();
+        let dummies = likely_dummies(&tx, &servers).unwrap();
+        assert_eq!(
+            dummies,
+            Dummies::from([("tw-0.6+udp://1.1.1.1:8303", "Tee (d)")])
+        );
+        maps::update(&tx, &servers, &dummies, 60, 1).unwrap();
+        activity::update(&tx, &servers, &dummies, 60, 1).unwrap();
+        tx.commit().unwrap();
+
+        let playtime: i64 = conn
+            .query_row(
+                "SELECT player_minutes FROM map_playtime WHERE map = 'Kobra'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(playtime, 3);
+        let regions: Vec<(String, String)> = conn
+            .prepare("SELECT name, region FROM player_regions ORDER BY name, region")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            regions,
+            [
+                ("Tee".to_string(), "eu:de".to_string()),
+                ("Tee (d)".to_string(), "as:cn".to_string()),
+                ("other".to_string(), "eu:de".to_string()),
+            ]
+        );
+    }
+}
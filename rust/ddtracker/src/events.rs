@@ -0,0 +1,370 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use log::{error, info};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+use crate::model::ServerList;
+use crate::skins::SkinChange;
+
+// what changed between two ticks, published once the tick is committed. presence is diffed in
+// memory against the previous tick, so the first tick after a start only sets the baseline instead
+// of announcing everyone that is already online. stream subscribers that fall behind miss events,
+// the jsonl file gets all of them.
+
+/// Events a slow stream subscriber can fall behind by before it starts missing them
+const CHANNEL_CAPACITY: usize = 1024;
+
+pub type EventSender = broadcast::Sender<Event>;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    PlayerJoined {
+        name: String,
+        address: String,
+        map: String,
+        region: String,
+    },
+    PlayerLeft {
+        name: String,
+        address: String,
+        map: String,
+        region: String,
+    },
+    SkinChanged {
+        name: String,
+        region: String,
+        skin: Value,
+    },
+    ServerAppeared {
+        address: String,
+        name: String,
+        map: String,
+        region: String,
+    },
+    ServerDisappeared {
+        address: String,
+        name: String,
+        region: String,
+    },
+    MapChanged {
+        address: String,
+        from: String,
+        to: String,
+        region: String,
+    },
+}
+
+impl EventKind {
+    /// Same as the serialized `type`
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::PlayerJoined { .. } => "player_joined",
+            EventKind::PlayerLeft { .. } => "player_left",
+            EventKind::SkinChanged { .. } => "skin_changed",
+            EventKind::ServerAppeared { .. } => "server_appeared",
+            EventKind::ServerDisappeared { .. } => "server_disappeared",
+            EventKind::MapChanged { .. } => "map_changed",
+        }
+    }
+
+    /// The player the event is about, if any
+    pub fn player(&self) -> Option<&str> {
+        match self {
+            EventKind::PlayerJoined { name, .. }
+            | EventKind::PlayerLeft { name, .. }
+            | EventKind::SkinChanged { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    /// Unix timestamp in milliseconds
+    pub time: i64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+struct ServerState {
+    name: String,
+    map: String,
+    region: String,
+    players: HashSet<String>,
+}
+
+pub struct Events {
+    previous: Option<HashMap<String, ServerState>>,
+    sender: EventSender,
+    file: Option<String>,
+}
+
+impl Events {
+    pub fn new(file: Option<String>) -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            previous: None,
+            sender,
+            file,
+        }
+    }
+
+    pub fn sender(&self) -> EventSender {
+        self.sender.clone()
+    }
+
+    /// Diffs the servers against the previous tick and remembers them for the next one
+    pub fn diff(
+        &mut self,
+        servers_data: &ServerList,
+        skin_changes: &[SkinChange],
+        now: i64,
+    ) -> Vec<Event> {
+        let time = now * 60000;
+        let mut kinds = Vec::new();
+
+        let mut current: Vec<(String, ServerState)> = Vec::new();
+        for server in &servers_data.servers {
+            let Some(address) = server.address() else {
+                continue;
+            };
+            current.push((
+                address.to_string(),
+                ServerState {
+                    name: server.info.name.clone(),
+                    map: server.info.map.name.clone(),
+                    region: server.location.clone().unwrap_or_default(),
+                    players: server
+                        .info
+                        .clients
+                        .iter()
+                        .map(|client| client.name.clone())
+                        .collect(),
+                },
+            ));
+        }
+
+        if let Some(previous) = &self.previous {
+            let empty = HashSet::new();
+            for (address, server) in &current {
+                let before = previous.get(address);
+                match before {
+                    None => kinds.push(EventKind::ServerAppeared {
+                        address: address.clone(),
+                        name: server.name.clone(),
+                        map: server.map.clone(),
+                        region: server.region.clone(),
+                    }),
+                    Some(before) if before.map != server.map => kinds.push(EventKind::MapChanged {
+                        address: address.clone(),
+                        from: before.map.clone(),
+                        to: server.map.clone(),
+                        region: server.region.clone(),
+                    }),
+                    Some(_) => {}
+                }
+
+                if let Some(before) = before {
+                    for name in sorted(before.players.difference(&server.players)) {
+                        kinds.push(EventKind::PlayerLeft {
+                            name: name.clone(),
+                            address: address.clone(),
+                            map: before.map.clone(),
+                            region: before.region.clone(),
+                        });
+                    }
+                }
+                let before_players = before.map_or(&empty, |before| &before.players);
+                for name in sorted(server.players.difference(before_players)) {
+                    kinds.push(EventKind::PlayerJoined {
+                        name: name.clone(),
+                        address: address.clone(),
+                        map: server.map.clone(),
+                        region: server.region.clone(),
+                    });
+                }
+            }
+
+            let current_addresses: HashSet<&str> = current
+                .iter()
+                .map(|(address, _)| address.as_str())
+                .collect();
+            let mut gone: Vec<(&String, &ServerState)> = previous
+                .iter()
+                .filter(|(address, _)| !current_addresses.contains(address.as_str()))
+                .collect();
+            gone.sort_by_key(|(address, _)| *address);
+            for (address, server) in gone {
+                for name in sorted(server.players.iter()) {
+                    kinds.push(EventKind::PlayerLeft {
+                        name: name.clone(),
+                        address: address.clone(),
+                        map: server.map.clone(),
+                        region: server.region.clone(),
+                    });
+                }
+                kinds.push(EventKind::ServerDisappeared {
+                    address: address.clone(),
+                    name: server.name.clone(),
+                    region: server.region.clone(),
+                });
+            }
+        }
+
+        for change in skin_changes {
+            kinds.push(EventKind::SkinChanged {
+                name: change.name.clone(),
+                region: change.region.clone(),
+                skin: serde_json::from_str(&change.skin).unwrap_or(Value::Null),
+            });
+        }
+
+        self.previous = Some(current.into_iter().collect());
+        kinds.into_iter().map(|kind| Event { time, kind }).collect()
+    }
+
+    /// Sends the events to stream subscribers and appends them to the jsonl file
+    pub fn publish(&self, events: Vec<Event>) {
+        if events.is_empty() {
+            return;
+        }
+        if let Some(path) = &self.file {
+            if let Err(e) = append_jsonl(path, &events) {
+                error!("Failed to write events to {}: {}", path, e);
+            }
+        }
+        let count = events.len();
+        for event in events {
+            // only fails when nobody is subscribed
+            let _ = self.sender.send(event);
+        }
+        info!("Published {} events", count);
+    }
+}
+
+fn sorted<'a>(names: impl Iterator<Item = &'a String>) -> Vec<&'a String> {
+    let mut names: Vec<&String> = names.collect();
+    names.sort();
+    names
+}
+
+fn append_jsonl(path: &str, events: &[Event]) -> std::io::Result<()> {
+    let mut lines = String::new();
+    for event in events {
+        lines.push_str(&serde_json::to_string(event)?);
+        lines.push('\n');
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(lines.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model;
+
+    fn list(servers: &[(&str, &str, &[&str])]) -> ServerList {
+        let servers: Vec<String> = servers
+            .iter()
+            .map(|(address, map, names)| {
+                let clients: Vec<String> = names
+                    .iter()
+                    .map(|name| format!(r#"{{"name":"{}"}}"#, name))
+                    .collect();
+                format!(
+                    r#"{{"addresses":["{}"],"location":"eu:de","info":{{"name":"Server","map":{{"name":"{}"}},"clients":[{}]}}}}"#,
+                    address,
+                    map,
+                    clients.join(",")
+                )
+            })
+            .collect();
+        model::parse(&format!(r#"{{"servers":[{}]}}"#, servers.join(",")))
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn ticks_are_diffed_against_the_previous_one() {
+        let path =
+            std::env::temp_dir().join(format!("ddtracker-events-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut events = Events::new(Some(path.to_str().unwrap().to_string()));
+        let mut receiver = events.sender().subscribe();
+        let a = "tw-0.6+udp://1.1.1.1:8303";
+        let b = "tw-0.6+udp://2.2.2.2:8303";
+        let c = "tw-0.6+udp://3.3.3.3:8303";
+
+        // the first tick only sets the baseline, skin changes still go out
+        let skin = SkinChange {
+            name: "Tee".to_string(),
+            region: "eu:de".to_string(),
+            skin: r#"{"n":"pinky"}"#.to_string(),
+        };
+        let first = events.diff(
+            &list(&[(a, "Kobra", &["Tee", "Left"]), (b, "Linear", &["Gone"])]),
+            std::slice::from_ref(&skin),
+            100,
+        );
+        assert_eq!(
+            first.iter().map(|e| e.kind.name()).collect::<Vec<_>>(),
+            vec!["skin_changed"]
+        );
+        events.publish(first);
+
+        let second = events.diff(
+            &list(&[(a, "Multeasymap", &["Tee", "New"]), (c, "Epix", &["Fresh"])]),
+            &[],
+            101,
+        );
+        let names: Vec<(&str, Option<&str>)> = second
+            .iter()
+            .map(|e| (e.kind.name(), e.kind.player()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("map_changed", None),
+                ("player_left", Some("Left")),
+                ("player_joined", Some("New")),
+                ("server_appeared", None),
+                ("player_joined", Some("Fresh")),
+                ("player_left", Some("Gone")),
+                ("server_disappeared", None),
+            ]
+        );
+        assert!(second.iter().all(|e| e.time == 101 * 60000));
+        events.publish(second);
+
+        // subscribers and the file both get every event in order
+        let mut received = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            received.push(event.kind.name());
+        }
+        assert_eq!(received.len(), 8);
+        let lines: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines
+                .iter()
+                .map(|line| line["type"].as_str().unwrap())
+                .collect::<Vec<_>>(),
+            received
+        );
+        assert_eq!(lines[0]["skin"]["n"], "pinky");
+        assert_eq!(lines[1]["from"], "Kobra");
+        assert_eq!(lines[1]["to"], "Multeasymap");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
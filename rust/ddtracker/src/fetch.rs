@@ -0,0 +1,552 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+use log::{info, warn};
+use reqwest::Client;
+use serde::Deserialize;
+use tokio::task::JoinSet;
+use tokio::time::Duration;
+
+use crate::config::Config;
+use crate::model::{self, Server, ServerInfo, ServerList};
+use crate::udp::{self, Protocol, Target};
+
+// masters are either tried one after another until one answers with a fresh list (failover), or
+// all queried at once and merged by server address, earlier urls winning (parallel). a master that
+// returns the exact same body as on its previous tick is considered stale.
+//
+// the udp source asks the game servers themselves, the configured ones in udp mode. hybrid mode
+// polls the servers the masters list and uses their answers over the master's info, keeping the
+// skins, afk and team the masters know by client name. while the masters are unavailable it keeps
+// polling the servers of their last list, without skins.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FetchMode {
+    Failover,
+    Parallel,
+}
+
+impl std::str::FromStr for FetchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "failover" => Ok(FetchMode::Failover),
+            "parallel" => Ok(FetchMode::Parallel),
+            _ => Err(format!(
+                "Unknown fetch mode {}, expected failover or parallel",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Master,
+    Udp,
+    Hybrid,
+}
+
+impl std::str::FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "master" => Ok(Source::Master),
+            "udp" => Ok(Source::Udp),
+            "hybrid" => Ok(Source::Hybrid),
+            _ => Err(format!(
+                "Unknown source {}, expected master, udp or hybrid",
+                s
+            )),
+        }
+    }
+}
+
+pub struct Fetched {
+    pub servers: ServerList,
+    /// Server entries that were skipped because they didn't parse
+    pub malformed: usize,
+    /// Urls that answered with a fresh list
+    pub sources: Vec<String>,
+    /// The body as received when the list comes from a single master unchanged
+    pub raw: Option<String>,
+}
+
+struct SourceList {
+    url: String,
+    body: String,
+    servers: ServerList,
+    malformed: usize,
+}
+
+pub struct Fetcher {
+    client: Client,
+    urls: Vec<String>,
+    mode: FetchMode,
+    last_hashes: HashMap<String, u64>,
+    source: Source,
+    udp_servers: Vec<String>,
+    udp_region: Option<String>,
+    udp_regions: Vec<String>,
+    udp_timeout: Duration,
+    /// The last list the masters returned
+    last_list: Option<ServerList>,
+}
+
+impl Fetcher {
+    pub fn new(client: Client, config: &Config) -> Self {
+        Self {
+            client,
+            urls: config.servers_urls.clone(),
+            mode: config.fetch_mode,
+            last_hashes: HashMap::new(),
+            source: config.source,
+            udp_servers: config.udp_servers.clone(),
+            udp_region: config.udp_region.clone(),
+            udp_regions: config.udp_regions.clone(),
+            udp_timeout: Duration::from_millis(config.udp_timeout),
+            last_list: None,
+        }
+    }
+
+    pub async fn fetch(&mut self) -> Result<Fetched, Box<dyn std::error::Error>> {
+        match self.source {
+            Source::Master => self.fetch_masters().await,
+            Source::Udp => self.poll_servers(merge(Vec::new()), false).await,
+            Source::Hybrid => match self.fetch_masters().await {
+                Ok(fetched) => {
+                    self.last_list = Some(fetched.servers.clone());
+                    self.poll_servers(fetched, true).await
+                }
+                Err(e) => {
+                    let Some(servers) = self.last_list.clone() else {
+                        return Err(e);
+                    };
+                    warn!("{}, polling the servers of the last list", e);
+                    let fetched = Fetched {
+                        servers,
+                        malformed: 0,
+                        sources: Vec::new(),
+                        raw: None,
+                    };
+                    self.poll_servers(fetched, false).await
+                }
+            },
+        }
+    }
+
+    async fn fetch_masters(&mut self) -> Result<Fetched, Box<dyn std::error::Error>> {
+        let lists = match self.mode {
+            FetchMode::Failover => self.fetch_failover().await,
+            FetchMode::Parallel => self.fetch_parallel().await,
+        };
+        if lists.is_empty() {
+            return Err("No master server returned a fresh server list".into());
+        }
+        Ok(merge(lists))
+    }
+
+    async fn fetch_failover(&mut self) -> Vec<SourceList> {
+        for url in self.urls.clone() {
+            let result = fetch_body(&self.client, &url).await;
+            if let Some(list) = self.accept(&url, result) {
+                return vec![list];
+            }
+        }
+        Vec::new()
+    }
+
+    async fn fetch_parallel(&mut self) -> Vec<SourceList> {
+        let mut set = JoinSet::new();
+        for (index, url) in self.urls.iter().enumerate() {
+            let client = self.client.clone();
+            let url = url.clone();
+            set.spawn(async move { (index, fetch_body(&client, &url).await) });
+        }
+
+        let mut results = Vec::new();
+        while let Some(joined) = set.join_next().await {
+            match joined {
+                Ok(result) => results.push(result),
+                Err(e) => warn!("Fetch task failed: {}", e),
+            }
+        }
+        results.sort_by_key(|(index, _)| *index);
+
+        let mut lists = Vec::new();
+        for (index, result) in results {
+            let url = self.urls[index].clone();
+            if let Some(list) = self.accept(&url, result) {
+                lists.push(list);
+            }
+        }
+        lists
+    }
+
+    /// Overlays the listed servers with their own answers and adds the configured servers. With a
+    /// `fresh` master list, servers that don't answer keep the master's info, otherwise they are
+    /// left out.
+    async fn poll_servers(
+        &self,
+        mut fetched: Fetched,
+        fresh: bool,
+    ) -> Result<Fetched, Box<dyn std::error::Error>> {
+        let listed = fetched.servers.servers.len();
+        let known: HashSet<String> = fetched
+            .servers
+            .servers
+            .iter()
+            .flat_map(|server| server.addresses.iter().cloned())
+            .collect();
+        for address in &self.udp_servers {
+            let address = if address.contains("://") {
+                address.clone()
+            } else {
+                format!("tw-0.6+udp://{}", address)
+            };
+            if !known.contains(&address) {
+                fetched.servers.servers.push(Server {
+                    addresses: vec![address],
+                    location: self.udp_region.clone(),
+                    info: ServerInfo::default(),
+                });
+            }
+        }
+
+        let mut candidates = Vec::new();
+        for (index, server) in fetched.servers.servers.iter().enumerate() {
+            if index < listed && !self.polls_region(server.location.as_deref()) {
+                continue;
+            }
+            let parsed: Vec<Target> = server
+                .addresses
+                .iter()
+                .filter_map(|address| Target::parse(address))
+                .collect();
+            candidates.push((index, parsed));
+        }
+        let sockets = udp::Sockets::bind(
+            candidates
+                .iter()
+                .flat_map(|(_, parsed)| parsed.iter().map(|target| &target.address)),
+        )
+        .await;
+
+        // an address of a family the host can bind first, 0.6 before 0.7
+        let mut polled = Vec::new();
+        let mut targets = Vec::new();
+        for (index, parsed) in candidates {
+            let target = parsed.into_iter().min_by_key(|target| {
+                (
+                    !sockets.supports(&target.address),
+                    target.protocol != Protocol::Legacy,
+                )
+            });
+            match target {
+                Some(target) => {
+                    polled.push(index);
+                    targets.push(target);
+                }
+                None => warn!(
+                    "No pollable address for {:?}",
+                    fetched.servers.servers[index].addresses
+                ),
+            }
+        }
+
+        let answers = udp::poll(&sockets, &targets, self.udp_timeout).await;
+        let mut answered = HashSet::new();
+        for (index, answer) in polled.iter().zip(answers) {
+            if let Some(info) = answer {
+                overlay(&mut fetched.servers.servers[*index], info, fresh);
+                answered.insert(*index);
+            }
+        }
+        info!(
+            "{} of {} polled servers answered",
+            answered.len(),
+            polled.len()
+        );
+        if answered.is_empty() && !fresh {
+            return Err("No game server answered".into());
+        }
+
+        let mut index = 0;
+        fetched.servers.servers.retain(|_| {
+            let keep = (fresh && index < listed) || answered.contains(&index);
+            index += 1;
+            keep
+        });
+        fetched.sources.push("udp".to_string());
+        fetched.raw = None;
+        Ok(fetched)
+    }
+
+    /// Whether a master listed server is polled in hybrid mode
+    fn polls_region(&self, location: Option<&str>) -> bool {
+        self.udp_regions.is_empty()
+            || location.is_some_and(|location| {
+                self.udp_regions.iter().any(|region| {
+                    location == region
+                        || location
+                            .strip_prefix(region.as_str())
+                            .is_some_and(|rest| rest.starts_with(':'))
+                })
+            })
+    }
+
+    /// Parses a fetched body, dropping it if the request failed, it doesn't parse or it's stale
+    fn accept(&mut self, url: &str, result: Result<String, reqwest::Error>) -> Option<SourceList> {
+        let body = match result {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Failed to fetch server list from {}: {}", url, e);
+                return None;
+            }
+        };
+
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        let hash = hasher.finish();
+        if self.last_hashes.insert(url.to_string(), hash) == Some(hash) {
+            warn!(
+                "Server list from {} has not changed since the last tick, skipping it",
+                url
+            );
+            return None;
+        }
+
+        match model::parse(&body) {
+            Ok((servers, issues)) => {
+                let malformed = issues.iter().filter(|issue| issue.client.is_none()).count();
+                if !issues.is_empty() {
+                    warn!(
+                        "Skipped {} malformed server entries out of {} and {} malformed clients from {}",
+                        malformed,
+                        malformed + servers.servers.len(),
+                        issues.len() - malformed,
+                        url
+                    );
+                    for issue in &issues {
+                        warn!("Malformed server entry: {}", issue);
+                    }
+                }
+                info!("Fetched {} servers from {}", servers.servers.len(), url);
+                Some(SourceList {
+                    url: url.to_string(),
+                    body,
+                    servers,
+                    malformed,
+                })
+            }
+            Err(e) => {
+                warn!("Failed to parse server list from {}: {}", url, e);
+                None
+            }
+        }
+    }
+}
+
+async fn fetch_body(client: &Client, url: &str) -> Result<String, reqwest::Error> {
+    client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await
+}
+
+/// Replaces a server's info with its own answer, carrying over what only the masters know
+fn overlay(server: &mut Server, mut info: ServerInfo, fresh: bool) {
+    if fresh {
+        let known: HashMap<&str, &model::Client> = server
+            .info
+            .clients
+            .iter()
+            .map(|client| (client.name.as_str(), client))
+            .collect();
+        for client in &mut info.clients {
+            if let Some(known) = known.get(client.name.as_str()) {
+                client.skin = known.skin.clone();
+                client.afk = known.afk;
+                client.team = known.team;
+            }
+        }
+        if info.map.name == server.info.map.name {
+            info.map.sha256 = server.info.map.sha256.clone();
+            info.map.size = info.map.size.or(server.info.map.size);
+        }
+    }
+    server.info = info;
+}
+
+/// Merges lists by server address, a server already listed by an earlier source is skipped
+fn merge(lists: Vec<SourceList>) -> Fetched {
+    let mut merged = Fetched {
+        servers: ServerList::default(),
+        malformed: 0,
+        sources: Vec::new(),
+        raw: None,
+    };
+    if let [list] = lists.as_slice() {
+        merged.raw = Some(list.body.clone());
+    }
+    let mut seen: HashSet<String> = HashSet::new();
+
+    for list in lists {
+        for server in list.servers.servers {
+            if server
+                .addresses
+                .iter()
+                .any(|address| seen.contains(address))
+            {
+                continue;
+            }
+            seen.extend(server.addresses.iter().cloned());
+            merged.servers.servers.push(server);
+        }
+        merged.malformed += list.malformed;
+        merged.sources.push(list.url);
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use axum::Router;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// A master stand-in serving whatever status and body are set, the url of its list
+    async fn master(response: Arc<Mutex<(StatusCode, String)>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = Router::new().route(
+            "/servers.json",
+            get(move || {
+                let response = response.clone();
+                async move { response.lock().unwrap().clone() }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}/servers.json", address)
+    }
+
+    /// A url nothing listens on
+    async fn down() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        format!("http://{}/servers.json", address)
+    }
+
+    fn list(servers: &[(&str, &str)]) -> String {
+        let servers: Vec<String> = servers
+            .iter()
+            .map(|(address, name)| {
+                format!(
+                    r#"{{"addresses":["{}"],"location":"eu:de","info":{{"name":"{}","clients":[]}}}}"#,
+                    address, name
+                )
+            })
+            .collect();
+        format!(r#"{{"servers":[{}]}}"#, servers.join(","))
+    }
+
+    fn serving(body: String) -> Arc<Mutex<(StatusCode, String)>> {
+        Arc::new(Mutex::new((StatusCode::OK, body)))
+    }
+
+    fn fetcher(urls: &[&str], mode: FetchMode) -> Fetcher {
+        let config = Config {
+            servers_urls: urls.iter().map(|url| url.to_string()).collect(),
+            fetch_mode: mode,
+            source: Source::Master,
+            ..Config::default()
+        };
+        Fetcher::new(Client::new(), &config)
+    }
+
+    fn names(fetched: &Fetched) -> Vec<&str> {
+        fetched
+            .servers
+            .servers
+            .iter()
+            .map(|server| server.info.name.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn failover_uses_the_next_master_when_one_is_down() {
+        let first = down().await;
+        let second = master(serving(list(&[("tw-0.6+udp://1.1.1.1:8303", "second")]))).await;
+        let mut fetcher = fetcher(&[&first, &second], FetchMode::Failover);
+
+        let fetched = fetcher.fetch().await.unwrap();
+        assert_eq!(fetched.sources, [second.as_str()]);
+        assert_eq!(names(&fetched), ["second"]);
+        assert!(fetched.raw.is_some());
+    }
+
+    #[tokio::test]
+    async fn failover_skips_a_failing_or_stale_master() {
+        let first_response = serving(list(&[("tw-0.6+udp://1.1.1.1:8303", "first")]));
+        let first = master(first_response.clone()).await;
+        let second_response = serving(list(&[("tw-0.6+udp://1.1.1.1:8303", "second")]));
+        let second = master(second_response.clone()).await;
+        let mut fetcher = fetcher(&[&first, &second], FetchMode::Failover);
+
+        let fetched = fetcher.fetch().await.unwrap();
+        assert_eq!(fetched.sources, [first.as_str()]);
+        assert_eq!(names(&fetched), ["first"]);
+
+        // same body as last tick, the first master is stale
+        let fetched = fetcher.fetch().await.unwrap();
+        assert_eq!(fetched.sources, [second.as_str()]);
+        assert_eq!(names(&fetched), ["second"]);
+
+        // an error status counts as down
+        first_response.lock().unwrap().0 = StatusCode::INTERNAL_SERVER_ERROR;
+        second_response.lock().unwrap().1 = list(&[("tw-0.6+udp://1.1.1.1:8303", "second again")]);
+        let fetched = fetcher.fetch().await.unwrap();
+        assert_eq!(fetched.sources, [second.as_str()]);
+        assert_eq!(names(&fetched), ["second again"]);
+
+        // both stale or down
+        assert!(fetcher.fetch().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn parallel_merge_keeps_the_earlier_masters_entry() {
+        let first = master(serving(list(&[
+            ("tw-0.6+udp://1.1.1.1:8303", "first"),
+            ("tw-0.6+udp://2.2.2.2:8303", "only first"),
+        ])))
+        .await;
+        let second = master(serving(list(&[
+            ("tw-0.6+udp://3.3.3.3:8303", "only second"),
+            ("tw-0.6+udp://1.1.1.1:8303", "second"),
+        ])))
+        .await;
+        let third = down().await;
+        let mut fetcher = fetcher(&[&first, &third, &second], FetchMode::Parallel);
+
+        let fetched = fetcher.fetch().await.unwrap();
+        assert_eq!(fetched.sources, [first.as_str(), second.as_str()]);
+        assert_eq!(names(&fetched), ["first", "only first", "only second"]);
+        // merged from two masters, there is no single body to keep
+        assert!(fetched.raw.is_none());
+    }
+}
@@ -0,0 +1,196 @@
+use log::info;
+use rusqlite::{params, Connection, Transaction};
+
+use crate::config::Config;
+use crate::dummies::Dummies;
+use crate::model::ServerList;
+use crate::skins::SkinChange;
+use crate::{
+    activity, aliases, clans, coplay, dummies, maps, population, rotations, servers, sessions,
+    skin_stats, skins, teams,
+};
+
+// the trackers that turn a server list into table rows. live ticks run all of them, a replay from
+// the archive can pick some, so every tracker names the tables it owns. a tick stands for the
+// minutes since the one before it, trackers that add up time count those instead of one. the
+// likely dummies are looked up once per tick for the trackers that leave them out. trackers use cached
+// statements, the connection's cache has to hold all of them or every tick prepares them again.
+
+/// Prepared statement cache size for connections that run the trackers
+pub const STATEMENT_CACHE_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tracker {
+    Skins,
+    SkinStats,
+    Sessions,
+    Population,
+    Maps,
+    Clans,
+    Servers,
+    Rotations,
+    Coplay,
+    Teams,
+    Activity,
+    Dummies,
+    Aliases,
+}
+
+impl Tracker {
+    pub const ALL: [Tracker; 13] = [
+        Tracker::Skins,
+        Tracker::SkinStats,
+        Tracker::Sessions,
+        Tracker::Population,
+        Tracker::Maps,
+        Tracker::Clans,
+        Tracker::Servers,
+        Tracker::Rotations,
+        Tracker::Coplay,
+        Tracker::Teams,
+        Tracker::Activity,
+        Tracker::Dummies,
+        Tracker::Aliases,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tracker::Skins => "skins",
+            Tracker::SkinStats => "skin_stats",
+            Tracker::Sessions => "sessions",
+            Tracker::Population => "population",
+            Tracker::Maps => "maps",
+            Tracker::Clans => "clans",
+            Tracker::Servers => "servers",
+            Tracker::Rotations => "rotations",
+            Tracker::Coplay => "coplay",
+            Tracker::Teams => "teams",
+            Tracker::Activity => "activity",
+            Tracker::Dummies => "dummies",
+            Tracker::Aliases => "aliases",
+        }
+    }
+
+    /// Tables written only by this tracker
+    pub fn tables(&self) -> &'static [&'static str] {
+        match self {
+            Tracker::Skins => &["clients", "skin_history"],
+            Tracker::SkinStats => &[
+                "skin_popularity",
+                "skin_colors",
+                "skin_players",
+                "skin_color_players",
+            ],
+            Tracker::Sessions => &["sessions"],
+            Tracker::Population => &["population", "population_rollups"],
+            Tracker::Maps => &["map_playtime", "map_versions"],
+            Tracker::Clans => &["clan_members", "player_clans"],
+            Tracker::Servers => &["servers", "server_changes", "server_listings"],
+            Tracker::Rotations => &["map_changes", "map_stints"],
+            Tracker::Coplay => &["coplay"],
+            Tracker::Teams => &["teams", "team_members"],
+            Tracker::Activity => &["player_activity", "player_regions"],
+            Tracker::Dummies => &["dummy_links", "dummy_clients", "dummy_servers"],
+            Tracker::Aliases => &["alias_links", "alias_evidence", "alias_clients"],
+        }
+    }
+}
+
+impl Tracker {
+    /// Closes the rows of trackers that keep things open from tick to tick (sessions, listings, map
+    /// stints, teams), the number closed
+    pub fn close_all(&self, conn: &Connection) -> rusqlite::Result<usize> {
+        match self {
+            Tracker::Sessions => sessions::close_all(conn),
+            Tracker::Servers => servers::close_all(conn),
+            Tracker::Rotations => rotations::close_all(conn),
+            Tracker::Teams => teams::close_all(conn),
+            _ => Ok(0),
+        }
+    }
+}
+
+impl Tracker {
+    /// Leaves the likely dummies out, using the links the dummies tracker learned so far
+    pub fn uses_dummies(&self) -> bool {
+        matches!(
+            self,
+            Tracker::SkinStats | Tracker::Population | Tracker::Maps | Tracker::Activity
+        )
+    }
+}
+
+impl std::str::FromStr for Tracker {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Tracker::ALL
+            .into_iter()
+            .find(|tracker| tracker.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Tracker::ALL.iter().map(Tracker::name).collect();
+                format!(
+                    "Unknown tracker {}, expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Runs the given trackers over one tick's server list, which stands for `minutes` of play
+pub fn run(
+    tx: &Transaction,
+    servers_data: &ServerList,
+    now: i64,
+    minutes: i64,
+    config: &Config,
+    trackers: &[Tracker],
+) -> rusqlite::Result<Vec<SkinChange>> {
+    let mut skin_changes = Vec::new();
+    let dummies = if trackers.iter().any(Tracker::uses_dummies) {
+        dummies::likely_dummies(tx, servers_data)?
+    } else {
+        Dummies::new()
+    };
+    for tracker in trackers {
+        match tracker {
+            Tracker::Skins => {
+                skin_changes = skins::update(tx, servers_data, now, config.skin_change_delay)?
+            }
+            Tracker::SkinStats => skin_stats::update(tx, servers_data, &dummies, now)?,
+            Tracker::Sessions => sessions::update(tx, servers_data, now)?,
+            Tracker::Population => population::update(
+                tx,
+                servers_data,
+                &dummies,
+                now,
+                config.population_retention(),
+            )?,
+            Tracker::Maps => maps::update(tx, servers_data, &dummies, now, minutes)?,
+            Tracker::Clans => clans::update(tx, servers_data, now)?,
+            Tracker::Servers => servers::update(tx, servers_data, now)?,
+            Tracker::Rotations => rotations::update(tx, servers_data, now)?,
+            Tracker::Coplay => coplay::update(tx, servers_data, now, minutes)?,
+            Tracker::Teams => teams::update(tx, servers_data, now)?,
+            Tracker::Activity => activity::update(tx, servers_data, &dummies, now, minutes)?,
+            Tracker::Dummies => dummies::update(tx, servers_data, now)?,
+            Tracker::Aliases => aliases::update(tx, servers_data, now)?,
+        }
+        info!("Updated {}", tracker.name());
+    }
+    Ok(skin_changes)
+}
+
+/// Whether `now` is the first tick of its day the daily job `key` sees, which marks the day as done.
+/// The day is kept in `info`, so a missed midnight tick only moves the job to the next tick.
+pub fn first_tick_of_day(tx: &Transaction, key: &str, now: i64) -> rusqlite::Result<bool> {
+    let day = now - now % (24 * 60);
+    let changed = tx
+        .prepare_cached(
+            "INSERT INTO info (key, value) VALUES (?1, ?2)
+            ON CONFLICT (key) DO UPDATE SET value = excluded.value WHERE value IS NOT excluded.value",
+        )?
+        .execute(params![key, day])?;
+    Ok(changed > 0)
+}
@@ -1,221 +1,460 @@
 use std::str::FromStr;
 
-use chrono::Utc;
+use chrono::{TimeZone, Utc};
 use cron::Schedule;
-use env_logger;
-use log::{error, info};
+use log::{error, info, warn};
 use reqwest::Client;
-use rusqlite::{params, Connection, Transaction};
-use serde_json::{Map, Value};
+use rusqlite::{params, Connection, ErrorCode, Transaction};
 use tokio::time::Duration;
 
-const SERVERS_URL: &str = "https://master1.ddnet.org/ddnet/15/servers.json";
-const CRON_EXPRESSION: &str = "0 * * * * *";
+use crate::archive::Archive;
+use crate::config::Config;
+use crate::events::Events;
+use crate::fetch::{Fetched, Fetcher};
+use crate::ingest::Tracker;
+use crate::skins::SkinChange;
+use crate::watch::Watchers;
+
+mod activity;
+mod aliases;
+mod api;
+mod archive;
+mod bench;
+mod clans;
+mod config;
+mod coplay;
+mod dummies;
+mod events;
+mod fetch;
+mod ingest;
+mod maps;
+mod migrations;
+mod model;
+mod population;
+mod replay;
+mod rotations;
+mod servers;
+mod sessions;
+mod skin_stats;
+mod skins;
+mod teams;
+mod udp;
+mod watch;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
+
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    // leading arguments that aren't options are a subcommand, like `watch list`
+    let command: Vec<String> = args
+        .iter()
+        .take_while(|arg| !arg.starts_with('-'))
+        .cloned()
+        .collect();
+    args.drain(..command.len());
+    let mut only = None;
+    let mut reset = false;
+    let mut status = false;
+    let mut generate = None;
+    let mut clients = None;
+    let mut ticks = None;
+    match command.first().map(String::as_str) {
+        Some("replay") => {
+            only = take_option(&mut args, "--only")?;
+            reset = take_flag(&mut args, "--reset");
+        }
+        Some("migrate") => status = take_flag(&mut args, "--status"),
+        Some("bench") => {
+            generate = take_option(&mut args, "--generate")?;
+            clients = take_option(&mut args, "--clients")?;
+            ticks = take_option(&mut args, "--ticks")?;
+        }
+        _ => {}
+    }
+    let Some(config) = Config::load(&args)? else {
+        return Ok(());
+    };
+    let schedule = Schedule::from_str(&config.cron)
+        .map_err(|e| format!("Failed to parse CRON expression {}: {}", config.cron, e))?;
+
+    // the benchmark brings its own database
+    if command.first().is_some_and(|command| command == "bench") {
+        let clients = clients.as_deref().unwrap_or("10000");
+        let clients = clients
+            .parse()
+            .map_err(|e| format!("Invalid client count {}: {}", clients, e))?;
+        let ticks = ticks.as_deref().unwrap_or("10");
+        let ticks = ticks
+            .parse()
+            .map_err(|e| format!("Invalid tick count {}: {}", ticks, e))?;
+        return match (command.as_slice(), generate) {
+            ([_], Some(path)) => bench::generate(&path, clients),
+            ([_, path], None) => bench::run(bench::load(path)?, ticks, &config),
+            ([_], None) => {
+                println!(
+                    "No recorded list given, using {} generated clients",
+                    clients
+                );
+                bench::run(bench::fixture(clients), ticks, &config)
+            }
+            _ => {
+                eprintln!("{}", bench::USAGE);
+                Err("Invalid bench command".into())
+            }
+        };
+    }
+
     info!("Starting ddtracker");
+    info!("Using database {}", config.database);
+    if config.dry_run {
+        info!("Dry run, nothing will be written");
+    }
 
-    let mut conn = Connection::open("./cache/ddtracker.db")?;
+    let mut conn = Connection::open(&config.database)?;
     conn.execute_batch("PRAGMA journal_mode = WAL;")?;
-    conn.execute_batch(
-        "
-        CREATE TABLE IF NOT EXISTS info (key TEXT PRIMARY KEY, value TEXT);
-        CREATE TABLE IF NOT EXISTS clients (name TEXT, region TEXT, current_skin TEXT, current_skin_time INTEGER, PRIMARY KEY (name, region));
-        CREATE INDEX IF NOT EXISTS clients_name_region ON clients (name, region);
-        CREATE INDEX IF NOT EXISTS clients_name ON clients (name);
-        "
-    )?;
-
-    let client = Client::new();
-    let schedule = Schedule::from_str(CRON_EXPRESSION).expect("Failed to parse CRON expression");
+    conn.busy_timeout(Duration::from_millis(config.db_busy_timeout))?;
+    conn.set_prepared_statement_cache_capacity(ingest::STATEMENT_CACHE_CAPACITY);
+    if command.first().is_some_and(|command| command == "migrate") {
+        return if status {
+            migrations::status(&conn)
+        } else {
+            migrations::run(&mut conn, false)
+        };
+    }
+    migrations::run(&mut conn, config.dry_run)?;
+
+    match command.first().map(String::as_str) {
+        None => {}
+        Some("watch") => return watch::command(&conn, &command[1..], &config),
+        Some("alias") => return aliases::command(&conn, &command[1..]),
+        Some("replay") => {
+            let [_, dir] = command.as_slice() else {
+                eprintln!("{}", replay::USAGE);
+                return Err("Invalid replay command".into());
+            };
+            let trackers = match only {
+                Some(only) => only
+                    .split(',')
+                    .map(|name| name.trim().parse())
+                    .collect::<Result<Vec<Tracker>, String>>()?,
+                None => Tracker::ALL.to_vec(),
+            };
+            return replay::run(&mut conn, dir, &trackers, reset, &config, &schedule);
+        }
+        Some(other) => return Err(format!("Unknown command {}, see --help", other).into()),
+    }
+
+    if !config.dry_run {
+        // nothing was tracked while we were down, don't let old sessions, listings, map stints and
+        // teams run on into this run
+        match sessions::close_all(&conn) {
+            Ok(closed) => info!("Closed {} sessions left open by the previous run", closed),
+            Err(e) => error!("Failed to close sessions left open: {}", e),
+        }
+        match servers::close_all(&conn) {
+            Ok(closed) => info!(
+                "Closed {} server listings left open by the previous run",
+                closed
+            ),
+            Err(e) => error!("Failed to close server listings left open: {}", e),
+        }
+        match rotations::close_all(&conn) {
+            Ok(closed) => info!("Closed {} map stints left open by the previous run", closed),
+            Err(e) => error!("Failed to close map stints left open: {}", e),
+        }
+        match teams::close_all(&conn) {
+            Ok(closed) => info!("Closed {} teams left open by the previous run", closed),
+            Err(e) => error!("Failed to close teams left open: {}", e),
+        }
+    }
+
+    let client = Client::builder()
+        .timeout(Duration::from_secs(config.http_timeout))
+        .build()?;
+    let mut fetcher = Fetcher::new(client.clone(), &config);
+    let mut events = Events::new(config.events_file.clone());
+    let mut watchers = Watchers::new(client.clone());
+    let archive = match &config.archive_dir {
+        Some(dir) if !config.dry_run => Some(Archive::new(dir)?),
+        _ => None,
+    };
+
+    if let (Some(listen), false) = (&config.api_listen, config.once) {
+        tokio::spawn(api::bind(listen, &config.database, events.sender()).await?);
+    }
+
+    if config.once {
+        info!("Running task once");
+        return task(
+            &mut fetcher,
+            &mut events,
+            &mut watchers,
+            archive.as_ref(),
+            &mut conn,
+            &config,
+            &schedule,
+        )
+        .await;
+    }
 
     loop {
         let now = Utc::now();
         if let Some(next) = schedule.upcoming(Utc).take(1).next() {
             info!("Next task scheduled at {}", next);
-            let until_next = next - now;
-            tokio::time::sleep(Duration::from_millis(until_next.num_milliseconds() as u64)).await;
+            let until_next = (next - now).to_std().unwrap_or_default();
+            tokio::time::sleep(until_next).await;
             info!("Running task");
-            task(&client, &mut conn).await?;
+            if let Err(e) = task(
+                &mut fetcher,
+                &mut events,
+                &mut watchers,
+                archive.as_ref(),
+                &mut conn,
+                &config,
+                &schedule,
+            )
+            .await
+            {
+                error!("Task failed: {}", e);
+            }
         }
     }
 }
 
-async fn task(client: &Client, conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+async fn task(
+    fetcher: &mut Fetcher,
+    events: &mut Events,
+    watchers: &mut Watchers,
+    archive: Option<&Archive>,
+    conn: &mut Connection,
+    config: &Config,
+    schedule: &Schedule,
+) -> Result<(), Box<dyn std::error::Error>> {
     let now = chrono::Utc::now().timestamp() / 60;
-    info!("Fetching server data from {}", SERVERS_URL);
-    let response = client.get(SERVERS_URL).send().await?;
-    let body = response.text().await;
-    match body {
-        Ok(body) => {
-            info!("Successfully fetched server data, parsing JSON");
-            let servers_data: Value = serde_json::from_str(&body)?;
-            info!("Parsed server data successfully");
-
-            let tx = conn.transaction()?;
-            info!("Started database transaction");
-
-            fn client_update_stmt<'b>(
-                tx: &'b Transaction<'b>,
-                name: &str,
-                region: &str,
-                current_skin: &str,
-                current_skin_time: i64,
-            ) {
-                let mut stmt = tx
-            .prepare(
-                "INSERT OR REPLACE INTO clients (name, region, current_skin, current_skin_time) VALUES (?, ?, ?, ?)",
-            )
-            .unwrap();
-                stmt.execute(params![name, region, current_skin, current_skin_time])
-                    .unwrap();
-            }
 
-            fn client_update_skin_time<'b>(
-                tx: &'b Transaction<'b>,
-                name: &str,
-                region: &str,
-                current_skin_time: i64,
-            ) {
-                let mut stmt = tx
-                    .prepare("UPDATE clients SET current_skin_time = ? WHERE name = ? AND region = ?")
-                    .unwrap();
-                stmt.execute(params![current_skin_time, name, region])
-                    .unwrap();
-            }
-
-            fn client_get_stmt<'a>(
-                tx: &'a Transaction<'a>,
-                name: &str,
-                region: &str,
-            ) -> Result<(String, i64), rusqlite::Error> {
-                let mut stmt = tx
-            .prepare(
-                "SELECT current_skin, current_skin_time FROM clients WHERE name = ? AND region = ?",
-            )
-            .unwrap();
-                return stmt.query_row(params![name, region], |row| {
-                    let current_skin: String = row.get(0)?;
-                    let current_skin_time: i64 = row.get(1)?;
-                    Ok((current_skin, current_skin_time))
-                });
-            }
-
-            fn update_time_info_stmt<'a>(tx: &'a Transaction<'a>, time: i64) {
-                let mut stmt = tx
-                    .prepare("INSERT OR REPLACE INTO info (key, value) VALUES (?, ?)")
-                    .unwrap();
-                stmt.execute(params!["last_update", time]).unwrap();
-            }
-
-            // first pass, check if the same skin is in use and update the skin time
-            if let Some(servers) = servers_data["servers"].as_array() {
-                for server in servers {
-                    if let (Some(info), Some(location)) =
-                        (server["info"].as_object(), server["location"].as_str())
-                    {
-                        if let Some(clients) = info["clients"].as_array() {
-                            for client in clients {
-                                if let (Some(name), Some(skin_info)) =
-                                    (client["name"].as_str(), client["skin"].as_object())
-                                {
-                                    let mut skin = Map::new();
-                                    if let Some(name) = skin_info.get("name") {
-                                        skin.insert("n".to_string(), name.clone());
-                                    }
-
-                                    if let Some(color_body) = skin_info.get("color_body") {
-                                        skin.insert("b".to_string(), color_body.clone());
-                                    }
-
-                                    if let Some(color_feet) = skin_info.get("color_feet") {
-                                        skin.insert("f".to_string(), color_feet.clone());
-                                    }
-                                    let skin = Value::Object(skin);
-                                    let skin_data = skin.to_string();
-
-                                    if let Ok((current_skin, _current_skin_time)) =
-                                        client_get_stmt(&tx, &name, &location)
-                                    {
-                                        if current_skin == skin_data {
-                                            // same skin, update the skin time
-                                            client_update_skin_time(&tx, &name, &location, now);
-                                            info!("Updated skin time for {} in {}", name, location);
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
+    let mut attempt = 0;
+    let fetched = loop {
+        attempt += 1;
+        info!("Fetching server data, attempt {}", attempt);
+        match fetcher.fetch().await {
+            Ok(fetched) => break fetched,
+            Err(e) => {
+                record_error(conn, config, now, "fetch", attempt, &e.to_string());
+                if attempt > config.retries {
+                    return Err(e);
                 }
+                retry_backoff(config, attempt).await;
             }
+        }
+    };
+    info!(
+        "Fetched {} servers from {}",
+        fetched.servers.servers.len(),
+        fetched.sources.join(", ")
+    );
 
-            // second pass, update the skin if the current skin has not been seen in the last 10 minutes
-            if let Some(servers) = servers_data["servers"].as_array() {
-                for server in servers {
-                    if let (Some(info), Some(location)) =
-                        (server["info"].as_object(), server["location"].as_str())
-                    {
-                        if let Some(clients) = info["clients"].as_array() {
-                            for client in clients {
-                                if let (Some(name), Some(skin_info)) =
-                                    (client["name"].as_str(), client["skin"].as_object())
-                                {
-                                    let mut skin = Map::new();
-                                    if let Some(name) = skin_info.get("name") {
-                                        skin.insert("n".to_string(), name.clone());
-                                    }
-
-                                    if let Some(color_body) = skin_info.get("color_body") {
-                                        skin.insert("b".to_string(), color_body.clone());
-                                    }
-
-                                    if let Some(color_feet) = skin_info.get("color_feet") {
-                                        skin.insert("f".to_string(), color_feet.clone());
-                                    }
-                                    let skin = Value::Object(skin);
-                                    let skin_data = skin.to_string();
-                                    if let Ok((current_skin, current_skin_time)) =
-                                        client_get_stmt(&tx, &name, &location)
-                                    {
-                                        if current_skin != skin_data && current_skin_time + 5 < now
-                                        {
-                                            client_update_stmt(
-                                                &tx,
-                                                name,
-                                                location,
-                                                skin_data.as_str(),
-                                                now,
-                                            );
-                                            info!("Updated skin for {} in {}", name, location);
-                                        }
-                                    } else {
-                                        client_update_stmt(
-                                                &tx,
-                                                name,
-                                                location,
-                                                skin_data.as_str(),
-                                                now,
-                                            );
-                                        info!("Inserted skin for {} in {}", name, location);
-                                    }
-                                }
-                            }
-                        }
-                    }
+    // archived before writing, so a tick that fails to write can still be replayed later
+    if let Some(archive) = archive {
+        if let Err(e) = archive.store(now, &fetched) {
+            error!("Failed to archive tick {}: {}", now, e);
+        }
+    }
+
+    let mut attempt = 0;
+    let skin_changes = loop {
+        attempt += 1;
+        match write_tick(conn, &fetched, now, config, schedule) {
+            Ok(skin_changes) => break skin_changes,
+            Err(e) => {
+                record_error(conn, config, now, "write", attempt, &e.to_string());
+                if attempt > config.retries || !is_busy(&e) {
+                    return Err(e.into());
                 }
-                update_time_info_stmt(&tx, now);
-                info!("Updated last_update time to {}", now);
-                tx.commit()?;
-                info!("Committed transaction");
+                retry_backoff(config, attempt).await;
             }
         }
-        Err(e) => {
-            error!("Failed to fetch server list: {}", e);
-        }
+    };
+
+    // only committed ticks are published, a dry run just keeps the presence baseline current
+    let tick_events = events.diff(&fetched.servers, &skin_changes, now);
+    if !config.dry_run {
+        events.publish(tick_events);
     }
+
+    if let Err(e) = watchers.update(conn, &fetched.servers, now, config) {
+        error!("Failed to check the watchlist: {}", e);
+    }
+
     info!("Task completed");
     Ok(())
 }
+
+fn write_tick(
+    conn: &mut Connection,
+    fetched: &Fetched,
+    now: i64,
+    config: &Config,
+    schedule: &Schedule,
+) -> rusqlite::Result<Vec<SkinChange>> {
+    let servers_data = &fetched.servers;
+
+    let tx = conn.transaction()?;
+    info!("Started database transaction");
+
+    fn update_time_info_stmt(tx: &Transaction, time: i64) -> rusqlite::Result<()> {
+        let mut stmt = tx.prepare("INSERT OR REPLACE INTO info (key, value) VALUES (?, ?)")?;
+        stmt.execute(params!["last_update", time])?;
+        Ok(())
+    }
+
+    fn insert_tick_stmt(tx: &Transaction, time: i64, fetched: &Fetched) -> rusqlite::Result<()> {
+        let mut stmt = tx.prepare(
+            "INSERT OR REPLACE INTO ticks (time, sources, servers, malformed) VALUES (?, ?, ?, ?)",
+        )?;
+        stmt.execute(params![
+            time,
+            serde_json::to_string(&fetched.sources).unwrap_or_default(),
+            fetched.servers.servers.len(),
+            fetched.malformed
+        ])?;
+        Ok(())
+    }
+
+    fn last_tick_stmt(tx: &Transaction) -> rusqlite::Result<Option<i64>> {
+        tx.query_row("SELECT MAX(time) FROM ticks", [], |row| row.get(0))
+    }
+
+    let last = last_tick_stmt(&tx)?;
+    if let Some(last) = last {
+        if let Some((start, end)) = missed_ticks(schedule, last, now) {
+            warn!("Missed ticks from {} to {}", start, end);
+            tx.execute(
+                "INSERT INTO gaps (start_time, end_time) VALUES (?, ?)",
+                params![start, end],
+            )?;
+        }
+    }
+
+    let minutes = tick_minutes(schedule, last, now);
+    let skin_changes = ingest::run(&tx, servers_data, now, minutes, config, &Tracker::ALL)?;
+
+    insert_tick_stmt(&tx, now, fetched)?;
+    update_time_info_stmt(&tx, now)?;
+    info!("Updated last_update time to {}", now);
+    if config.dry_run {
+        tx.rollback()?;
+        info!("Dry run, rolled back transaction");
+    } else {
+        tx.commit()?;
+        info!("Committed transaction");
+    }
+    Ok(skin_changes)
+}
+
+/// The scheduled minutes between the last recorded tick and now that never got a tick
+fn missed_ticks(schedule: &Schedule, last: i64, now: i64) -> Option<(i64, i64)> {
+    let after = Utc.timestamp_opt(last * 60 + 59, 0).single()?;
+    let expected = schedule.after(&after).next()?.timestamp() / 60;
+    if expected < now {
+        Some((expected, now - 1))
+    } else {
+        None
+    }
+}
+
+/// Minutes a tick at `now` stands for, the time since the last tick but at most one scheduled
+/// interval, so the tick after a gap doesn't count the whole gap
+fn tick_minutes(schedule: &Schedule, last: Option<i64>, now: i64) -> i64 {
+    let interval = Utc
+        .timestamp_opt(now * 60, 0)
+        .single()
+        .and_then(|at| schedule.after(&at).next_back())
+        .map_or(1, |previous| now - previous.timestamp() / 60)
+        .max(1);
+    match last {
+        Some(last) if last < now => (now - last).min(interval),
+        _ => interval,
+    }
+}
+
+fn is_busy(e: &rusqlite::Error) -> bool {
+    matches!(
+        e.sqlite_error_code(),
+        Some(ErrorCode::DatabaseBusy) | Some(ErrorCode::DatabaseLocked)
+    )
+}
+
+async fn retry_backoff(config: &Config, attempt: u32) {
+    let delay = config.retry_backoff * 2u64.pow(attempt - 1);
+    warn!("Retrying in {}ms", delay);
+    tokio::time::sleep(Duration::from_millis(delay)).await;
+}
+
+/// Removes a command's own flag from the arguments, which are parsed as options afterwards
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let before = args.len();
+    args.retain(|arg| arg != name);
+    args.len() != before
+}
+
+/// Removes a command's own option and its value from the arguments
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, String> {
+    let Some(index) = args.iter().position(|arg| arg == name) else {
+        return Ok(None);
+    };
+    if args
+        .get(index + 1)
+        .is_none_or(|value| value.starts_with('-'))
+    {
+        return Err(format!("Missing value for {}", name));
+    }
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Ok(Some(value))
+}
+
+/// Keeps a record of failed attempts, failing to do so is only logged
+fn record_error(
+    conn: &Connection,
+    config: &Config,
+    time: i64,
+    stage: &str,
+    attempt: u32,
+    message: &str,
+) {
+    error!(
+        "Tick {} {} attempt {} failed: {}",
+        time, stage, attempt, message
+    );
+    if config.dry_run {
+        return;
+    }
+    if let Err(e) = conn.execute(
+        "INSERT INTO tick_errors (time, stage, attempt, message) VALUES (?, ?, ?, ?)",
+        params![time, stage, attempt, message],
+    ) {
+        error!("Failed to record tick error: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_stand_for_one_scheduled_interval_at_most() {
+        let every_minute = Schedule::from_str("0 * * * * *").unwrap();
+        let every_five = Schedule::from_str("0 */5 * * * *").unwrap();
+        let now = 28_928_160;
+
+        assert_eq!(tick_minutes(&every_minute, Some(now - 1), now), 1);
+        assert_eq!(tick_minutes(&every_minute, Some(now - 90), now), 1);
+        assert_eq!(tick_minutes(&every_minute, None, now), 1);
+        assert_eq!(tick_minutes(&every_five, Some(now - 5), now), 5);
+        assert_eq!(tick_minutes(&every_five, Some(now - 60), now), 5);
+        assert_eq!(tick_minutes(&every_five, None, now), 5);
+        // a late manual tick only counts what passed
+        assert_eq!(tick_minutes(&every_five, Some(now - 2), now), 2);
+    }
+}
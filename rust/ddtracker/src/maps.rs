@@ -0,0 +1,88 @@
+use std::collections::{HashMap, HashSet};
+
+use log::info;
+use rusqlite::{params, OptionalExtension, Transaction};
+
+use crate::dummies::Dummies;
+use crate::model::ServerList;
+
+// play time is counted in player-minutes, every tick adds its minutes for each player (not
+// spectator) on a server running the map, likely dummies of someone on the same server don't add any. versions
+// are keyed by sha256, servers that don't send one are only counted towards play time.
+
+pub fn update(
+    tx: &Transaction,
+    servers_data: &ServerList,
+    dummies: &Dummies,
+    now: i64,
+    minutes: i64,
+) -> rusqlite::Result<()> {
+    let mut playtime: HashMap<&str, i64> = HashMap::new();
+    let mut versions: HashMap<(&str, &str), i64> = HashMap::new();
+    let mut seen: HashSet<&str> = HashSet::new();
+
+    for server in &servers_data.servers {
+        let map = &server.info.map;
+        let address = server.address();
+        if map.name.is_empty() || address.is_some_and(|address| !seen.insert(address)) {
+            continue;
+        }
+
+        let players = server
+            .info
+            .clients
+            .iter()
+            .filter(|client| {
+                client.is_player
+                    && !address
+                        .is_some_and(|address| dummies.contains(&(address, client.name.as_str())))
+            })
+            .count() as i64;
+        *playtime.entry(map.name.as_str()).or_default() += players * minutes;
+
+        if let Some(sha256) = map.sha256.as_deref() {
+            versions.insert((map.name.as_str(), sha256), map.size.unwrap_or(0));
+        }
+    }
+
+    let day = now - now % (24 * 60);
+    let mut playtime_stmt = tx.prepare_cached(
+        "INSERT INTO map_playtime (map, day, player_minutes) VALUES (?, ?, ?)
+        ON CONFLICT (map, day) DO UPDATE SET player_minutes = player_minutes + excluded.player_minutes",
+    )?;
+    for (map, players) in &playtime {
+        if *players > 0 {
+            playtime_stmt.execute(params![map, day, players])?;
+        }
+    }
+
+    let mut touch_stmt =
+        tx.prepare_cached("UPDATE map_versions SET last_seen = ? WHERE map = ? AND sha256 = ?")?;
+    let mut known_stmt = tx.prepare_cached("SELECT 1 FROM map_versions WHERE map = ? LIMIT 1")?;
+    let mut insert_stmt = tx.prepare_cached(
+        "INSERT INTO map_versions (map, sha256, size, first_seen, last_seen) VALUES (?, ?, ?, ?, ?)",
+    )?;
+    for ((map, sha256), size) in &versions {
+        if touch_stmt.execute(params![now, map, sha256])? > 0 {
+            continue;
+        }
+
+        let known = known_stmt
+            .query_row(params![map], |_| Ok(()))
+            .optional()?
+            .is_some();
+        if known {
+            info!("Map update detected: {} ({})", map, sha256);
+        } else {
+            info!("New map detected: {} ({})", map, sha256);
+        }
+        insert_stmt.execute(params![map, sha256, size, now, now])?;
+    }
+
+    info!(
+        "Maps: {} maps in play, {} versions seen",
+        playtime.len(),
+        versions.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,312 @@
+use log::info;
+use rusqlite::Connection;
+
+// the schema version is sqlite's `user_version`, the number of migrations applied. each pending
+// migration runs in its own transaction together with the version bump, so a failed one leaves the
+// database at the previous version. migrations are only ever appended, never edited to give a
+// different schema. the first one
+// is the schema from before versioning and has to cope with tables that already exist. migrations
+// that free a lot of pages ask for a vacuum afterwards, it can't run inside their transaction.
+
+struct Migration {
+    name: &'static str,
+    sql: &'static str,
+    vacuum: bool,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        name: "initial schema",
+        sql: "
+        CREATE TABLE IF NOT EXISTS info (key TEXT PRIMARY KEY, value TEXT);
+        CREATE TABLE IF NOT EXISTS clients (name TEXT, region TEXT, current_skin TEXT, current_skin_time INTEGER, PRIMARY KEY (name, region));
+        CREATE INDEX IF NOT EXISTS clients_name_region ON clients (name, region);
+        CREATE INDEX IF NOT EXISTS clients_name ON clients (name);
+        CREATE TABLE IF NOT EXISTS skin_history (id INTEGER PRIMARY KEY, name TEXT, region TEXT, skin TEXT, first_seen INTEGER, last_seen INTEGER);
+        CREATE INDEX IF NOT EXISTS skin_history_name_region ON skin_history (name, region, first_seen);
+        CREATE TABLE IF NOT EXISTS sessions (id INTEGER PRIMARY KEY, name TEXT, address TEXT, map TEXT, region TEXT, start_time INTEGER, end_time INTEGER, online INTEGER);
+        CREATE INDEX IF NOT EXISTS sessions_name ON sessions (name, end_time);
+        CREATE INDEX IF NOT EXISTS sessions_address ON sessions (address, end_time);
+        CREATE INDEX IF NOT EXISTS sessions_online ON sessions (online);
+        CREATE TABLE IF NOT EXISTS population (scope TEXT, key TEXT, time INTEGER, players INTEGER, spectators INTEGER, PRIMARY KEY (scope, key, time));
+        CREATE INDEX IF NOT EXISTS population_time ON population (time);
+        CREATE TABLE IF NOT EXISTS population_rollups (scope TEXT, key TEXT, period TEXT, bucket INTEGER, samples INTEGER, players_sum INTEGER, players_max INTEGER, spectators_sum INTEGER, spectators_max INTEGER, PRIMARY KEY (scope, key, period, bucket));
+        CREATE TABLE IF NOT EXISTS map_playtime (map TEXT, day INTEGER, player_minutes INTEGER, PRIMARY KEY (map, day));
+        CREATE INDEX IF NOT EXISTS map_playtime_day ON map_playtime (day);
+        CREATE TABLE IF NOT EXISTS map_versions (map TEXT, sha256 TEXT, size INTEGER, first_seen INTEGER, last_seen INTEGER, PRIMARY KEY (map, sha256));
+        CREATE TABLE IF NOT EXISTS clan_members (clan TEXT, name TEXT, first_seen INTEGER, last_seen INTEGER, PRIMARY KEY (clan, name));
+        CREATE INDEX IF NOT EXISTS clan_members_name ON clan_members (name);
+        CREATE TABLE IF NOT EXISTS player_clans (name TEXT PRIMARY KEY, clan TEXT, since INTEGER, last_seen INTEGER);
+        CREATE INDEX IF NOT EXISTS player_clans_clan ON player_clans (clan);
+        CREATE TABLE IF NOT EXISTS ticks (time INTEGER PRIMARY KEY, sources TEXT, servers INTEGER, malformed INTEGER);
+        CREATE TABLE IF NOT EXISTS tick_errors (id INTEGER PRIMARY KEY, time INTEGER, stage TEXT, attempt INTEGER, message TEXT);
+        CREATE INDEX IF NOT EXISTS tick_errors_time ON tick_errors (time);
+        CREATE TABLE IF NOT EXISTS gaps (start_time INTEGER, end_time INTEGER);
+        CREATE TABLE IF NOT EXISTS watchlist (id INTEGER PRIMARY KEY, kind TEXT, target TEXT, webhook_url TEXT, cooldown INTEGER, created INTEGER);
+        ",
+        vacuum: false,
+    },
+    Migration {
+        name: "drop clients index duplicating the primary key",
+        sql: "DROP INDEX IF EXISTS clients_name_region;",
+        vacuum: false,
+    },
+    Migration {
+        name: "move skins into their own table",
+        sql: "
+        CREATE TABLE skins (id INTEGER PRIMARY KEY, name TEXT, body INTEGER, feet INTEGER);
+        CREATE UNIQUE INDEX skins_name_colors ON skins (name, body, feet);
+        INSERT OR IGNORE INTO skins (name, body, feet)
+            SELECT DISTINCT json_extract(skin, '$.n'), json_extract(skin, '$.b'), json_extract(skin, '$.f')
+            FROM (SELECT current_skin AS skin FROM clients UNION SELECT skin FROM skin_history);
+
+        CREATE TABLE clients_new (name TEXT, region TEXT, current_skin_id INTEGER REFERENCES skins (id), current_skin_time INTEGER, PRIMARY KEY (name, region));
+        INSERT INTO clients_new (name, region, current_skin_id, current_skin_time)
+            SELECT c.name, c.region, s.id, c.current_skin_time FROM clients c LEFT JOIN skins s
+            ON s.name IS json_extract(c.current_skin, '$.n') AND s.body IS json_extract(c.current_skin, '$.b') AND s.feet IS json_extract(c.current_skin, '$.f');
+        DROP TABLE clients;
+        ALTER TABLE clients_new RENAME TO clients;
+        CREATE INDEX clients_name ON clients (name);
+
+        CREATE TABLE skin_history_new (id INTEGER PRIMARY KEY, name TEXT, region TEXT, skin_id INTEGER REFERENCES skins (id), first_seen INTEGER, last_seen INTEGER);
+        INSERT INTO skin_history_new (id, name, region, skin_id, first_seen, last_seen)
+            SELECT h.id, h.name, h.region, s.id, h.first_seen, h.last_seen FROM skin_history h LEFT JOIN skins s
+            ON s.name IS json_extract(h.skin, '$.n') AND s.body IS json_extract(h.skin, '$.b') AND s.feet IS json_extract(h.skin, '$.f');
+        DROP TABLE skin_history;
+        ALTER TABLE skin_history_new RENAME TO skin_history;
+        CREATE INDEX skin_history_name_region ON skin_history (name, region, first_seen);
+        ",
+        vacuum: true,
+    },
+    Migration {
+        name: "daily skin popularity and color statistics",
+        sql: "
+        CREATE TABLE skin_popularity (day INTEGER, region TEXT, skin TEXT, players INTEGER, PRIMARY KEY (day, region, skin));
+        CREATE INDEX skin_popularity_region ON skin_popularity (region, day);
+        CREATE TABLE skin_colors (skin TEXT, part TEXT, day INTEGER, color INTEGER, players INTEGER, PRIMARY KEY (skin, part, day, color));
+        CREATE TABLE skin_players (day INTEGER, region TEXT, skin TEXT, name TEXT, PRIMARY KEY (day, region, skin, name));
+        CREATE TABLE skin_color_players (day INTEGER, skin TEXT, part TEXT, color INTEGER, name TEXT, PRIMARY KEY (day, skin, part, color, name));
+        ",
+        vacuum: false,
+    },
+    Migration {
+        name: "server metadata, changes and listings",
+        sql: "
+        CREATE TABLE servers (address TEXT PRIMARY KEY, addresses TEXT, region TEXT, name TEXT, game_type TEXT, version TEXT, passworded INTEGER, max_clients INTEGER, max_players INTEGER, first_seen INTEGER, last_seen INTEGER);
+        CREATE INDEX servers_region ON servers (region);
+        CREATE TABLE server_changes (id INTEGER PRIMARY KEY, address TEXT, time INTEGER, field TEXT, old_value TEXT, new_value TEXT);
+        CREATE INDEX server_changes_address ON server_changes (address, time);
+        CREATE TABLE server_listings (id INTEGER PRIMARY KEY, address TEXT, start_time INTEGER, end_time INTEGER, online INTEGER);
+        CREATE INDEX server_listings_address ON server_listings (address, end_time);
+        CREATE INDEX server_listings_online ON server_listings (online);
+        ",
+        vacuum: false,
+    },
+    Migration {
+        name: "map rotation log",
+        sql: "
+        CREATE TABLE map_changes (id INTEGER PRIMARY KEY, address TEXT, time INTEGER, previous_map TEXT, map TEXT, previous_players INTEGER, players INTEGER);
+        CREATE INDEX map_changes_address ON map_changes (address, time);
+        CREATE TABLE map_stints (id INTEGER PRIMARY KEY, address TEXT, map TEXT, start_time INTEGER, end_time INTEGER, ticks INTEGER, first_players INTEGER, last_players INTEGER, players_sum INTEGER, players_max INTEGER, online INTEGER);
+        CREATE INDEX map_stints_address ON map_stints (address, end_time);
+        CREATE INDEX map_stints_online ON map_stints (online);
+        ",
+        vacuum: false,
+    },
+    Migration {
+        name: "co-play graph",
+        sql: "
+        CREATE TABLE coplay (a TEXT, b TEXT, weight REAL, minutes INTEGER, last_seen INTEGER, PRIMARY KEY (a, b));
+        CREATE INDEX coplay_b ON coplay (b);
+        ",
+        vacuum: false,
+    },
+    Migration {
+        name: "ddrace teams",
+        sql: "
+        CREATE TABLE teams (id INTEGER PRIMARY KEY, address TEXT, map TEXT, team INTEGER, start_time INTEGER, end_time INTEGER, online INTEGER);
+        CREATE INDEX teams_online ON teams (online);
+        CREATE INDEX teams_map ON teams (map, end_time);
+        CREATE TABLE team_members (team_id INTEGER, name TEXT, first_seen INTEGER, last_seen INTEGER, PRIMARY KEY (team_id, name));
+        CREATE INDEX team_members_name ON team_members (name);
+        ",
+        vacuum: false,
+    },
+    Migration {
+        name: "player activity and regions",
+        sql: "
+        CREATE TABLE player_activity (name TEXT, hour INTEGER, minutes INTEGER, PRIMARY KEY (name, hour));
+        CREATE TABLE player_regions (name TEXT, region TEXT, minutes INTEGER, last_seen INTEGER, PRIMARY KEY (name, region));
+        ",
+        vacuum: false,
+    },
+    Migration {
+        name: "dummy links and population without dummies",
+        sql: "
+        CREATE TABLE dummy_links (owner TEXT, dummy TEXT, joins INTEGER, apart INTEGER, confidence REAL, first_seen INTEGER, last_seen INTEGER, PRIMARY KEY (owner, dummy));
+        CREATE INDEX dummy_links_dummy ON dummy_links (dummy);
+        CREATE TABLE dummy_clients (address TEXT, name TEXT, time INTEGER, PRIMARY KEY (address, name));
+        CREATE INDEX dummy_clients_name ON dummy_clients (name);
+        ALTER TABLE population ADD COLUMN people INTEGER;
+        ALTER TABLE population_rollups ADD COLUMN people_sum INTEGER;
+        ALTER TABLE population_rollups ADD COLUMN people_max INTEGER;
+        ",
+        vacuum: false,
+    },
+    Migration {
+        name: "alias candidates",
+        sql: "
+        CREATE TABLE alias_links (id INTEGER PRIMARY KEY, old_name TEXT, new_name TEXT, renames INTEGER, first_seen INTEGER, last_seen INTEGER, UNIQUE (old_name, new_name));
+        CREATE TABLE alias_evidence (link_id INTEGER, time INTEGER, address TEXT, map TEXT, clan TEXT, country INTEGER, skin TEXT);
+        CREATE INDEX alias_evidence_link ON alias_evidence (link_id, time);
+        CREATE TABLE alias_clients (address TEXT, name TEXT, clan TEXT, country INTEGER, skin TEXT, time INTEGER, PRIMARY KEY (address, name));
+        CREATE TABLE alias_decisions (old_name TEXT, new_name TEXT, status TEXT, decided INTEGER, PRIMARY KEY (old_name, new_name));
+        CREATE INDEX alias_decisions_new_name ON alias_decisions (new_name);
+        ",
+        vacuum: false,
+    },
+    Migration {
+        name: "dummy servers",
+        sql: "
+        CREATE TABLE dummy_servers (address TEXT PRIMARY KEY, time INTEGER);
+        INSERT INTO dummy_servers (address, time) SELECT address, MAX(time) FROM dummy_clients GROUP BY address;
+        ",
+        vacuum: false,
+    },
+];
+
+/// Schema version this build writes
+pub fn latest() -> i64 {
+    MIGRATIONS.len() as i64
+}
+
+pub fn current(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// Brings the database up to the latest version, refusing databases written by a newer build
+pub fn run(conn: &mut Connection, dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let version = current(conn)?;
+    if version > latest() {
+        return Err(format!(
+            "Database schema version {} is newer than the latest known version {}, refusing to start",
+            version,
+            latest()
+        )
+        .into());
+    }
+    if version < latest() && dry_run {
+        return Err(format!(
+            "Database schema version {} needs migrations up to {}, run `ddtracker migrate` first",
+            version,
+            latest()
+        )
+        .into());
+    }
+
+    let mut vacuum = false;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let target = index as i64 + 1;
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql)
+            .map_err(|e| format!("Migration {} ({}) failed: {}", target, migration.name, e))?;
+        tx.pragma_update(None, "user_version", target)?;
+        tx.commit()?;
+        info!(
+            "Migrated database to version {}: {}",
+            target, migration.name
+        );
+        vacuum |= migration.vacuum;
+    }
+    if vacuum {
+        info!("Vacuuming database to return freed space");
+        conn.execute_batch("VACUUM;")?;
+    }
+    Ok(())
+}
+
+/// Prints the version of the database and the state of every known migration
+pub fn status(conn: &Connection) -> Result<(), Box<dyn std::error::Error>> {
+    let version = current(conn)?;
+    println!(
+        "Schema version {}, latest known version {}",
+        version,
+        latest()
+    );
+    for (index, migration) in MIGRATIONS.iter().enumerate() {
+        let target = index as i64 + 1;
+        let state = if target <= version {
+            "applied"
+        } else {
+            "pending"
+        };
+        println!("{:>4}  {:<8} {}", target, state, migration.name);
+    }
+    if version > latest() {
+        println!("The database was migrated by a newer ddtracker");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baseline_database_migrates_without_losing_rows() {
+        // a database written before versioning, at user_version 0 with the initial schema
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0].sql).unwrap();
+        conn.execute_batch(
+            r#"
+            INSERT INTO clients VALUES ('Tee', 'eu', '{"n":"default","b":65408,"f":65280}', 100);
+            INSERT INTO clients VALUES ('Plain', 'eu', '{"n":"santa"}', 100);
+            INSERT INTO clients VALUES ('Null', 'eu', NULL, 100);
+            INSERT INTO clients VALUES ('Odd', 'cn', '"default"', 100);
+            INSERT INTO skin_history VALUES (1, 'Tee', 'eu', '{"n":"default","b":65408,"f":65280}', 50, 100);
+            INSERT INTO skin_history VALUES (2, 'Tee', 'cn', '{"n":"default","b":"65408","f":65280}', 60, 90);
+            INSERT INTO skin_history VALUES (3, 'Null', 'eu', NULL, 70, 100);
+            INSERT INTO skin_history VALUES (4, 'Odd', 'cn', '"default"', 80, 100);
+            INSERT INTO sessions VALUES (1, 'Tee', 'tw-0.6+udp://1.1.1.1:8303', 'Kobra', 'eu', 50, 100, 1);
+            "#,
+        )
+        .unwrap();
+
+        run(&mut conn, false).unwrap();
+        assert_eq!(current(&conn).unwrap(), latest());
+
+        let count = |conn: &Connection, table: &str| -> i64 {
+            conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(count(&conn, "clients"), 4);
+        assert_eq!(count(&conn, "skin_history"), 4);
+        assert_eq!(count(&conn, "sessions"), 1);
+
+        // colors written as text and as numbers are the same skin
+        let skins: Vec<Option<i64>> = conn
+            .prepare("SELECT skin_id FROM skin_history WHERE name = 'Tee' ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(skins.len(), 2);
+        assert!(skins[0].is_some());
+        assert_eq!(skins[0], skins[1]);
+        let skin: (String, i64, i64) = conn
+            .query_row(
+                "SELECT s.name, s.body, s.feet FROM clients c JOIN skins s ON s.id = c.current_skin_id WHERE c.name = 'Tee'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(skin, ("default".to_string(), 65408, 65280));
+
+        // migrating again is a no-op
+        run(&mut conn, false).unwrap();
+        assert_eq!(count(&conn, "clients"), 4);
+    }
+}
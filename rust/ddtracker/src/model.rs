@@ -0,0 +1,273 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+// typed view of the master's servers.json. everything that isn't needed to identify a server or a
+// client is optional, older servers leave out fields like sha256 or team. a malformed client only
+// drops itself, a malformed server anywhere else drops the server.
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ServerList {
+    pub servers: Vec<Server>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Server {
+    pub addresses: Vec<String>,
+    #[serde(default)]
+    pub location: Option<String>,
+    pub info: ServerInfo,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ServerInfo {
+    #[serde(default)]
+    pub max_clients: i64,
+    #[serde(default)]
+    pub max_players: i64,
+    #[serde(default)]
+    pub passworded: bool,
+    #[serde(default)]
+    pub game_type: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub map: MapInfo,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub clients: Vec<Client>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct MapInfo {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub size: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Client {
+    pub name: String,
+    #[serde(default)]
+    pub clan: String,
+    #[serde(default = "default_country")]
+    pub country: i64,
+    #[serde(default)]
+    pub score: i64,
+    #[serde(default = "default_is_player")]
+    pub is_player: bool,
+    #[serde(default)]
+    pub skin: Option<Skin>,
+    #[serde(default)]
+    pub afk: bool,
+    #[serde(default)]
+    pub team: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Skin {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub color_body: Option<i64>,
+    #[serde(default)]
+    pub color_feet: Option<i64>,
+}
+
+fn default_country() -> i64 {
+    -1
+}
+
+fn default_is_player() -> bool {
+    true
+}
+
+impl Server {
+    /// The first listed address, used as the key of a server everywhere
+    pub fn address(&self) -> Option<&str> {
+        self.addresses.first().map(String::as_str)
+    }
+}
+
+impl Client {
+    /// No clan, country or skin, nothing to tell this client apart from any other new player
+    pub fn is_anonymous(&self) -> bool {
+        let skin = self.skin.as_ref().and_then(|skin| skin.name.as_deref());
+        self.clan.is_empty() && self.country == -1 && matches!(skin, None | Some("default"))
+    }
+}
+
+impl Skin {
+    /// Compact form stored in the database, `{"n":..,"b":..,"f":..}` with missing fields left out
+    pub fn data(&self) -> String {
+        #[derive(Serialize)]
+        struct SkinData<'a> {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            n: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            b: Option<i64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            f: Option<i64>,
+        }
+
+        serde_json::to_string(&SkinData {
+            n: self.name.as_deref(),
+            b: self.color_body,
+            f: self.color_feet,
+        })
+        .unwrap_or_default()
+    }
+}
+
+/// A server entry or one of its clients that could not be parsed and was left out of the list
+#[derive(Debug)]
+pub struct ParseIssue {
+    pub index: usize,
+    /// Position of the skipped client in the server's list, `None` when the whole server was skipped
+    pub client: Option<usize>,
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ParseIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path == "." {
+            write!(f, "servers[{}]: {}", self.index, self.message)
+        } else {
+            write!(
+                f,
+                "servers[{}] at `{}`: {}",
+                self.index, self.path, self.message
+            )
+        }
+    }
+}
+
+/// Parses a servers.json body. Only a broken top level fails the whole list, malformed server and
+/// client entries are skipped and returned as issues.
+pub fn parse(body: &str) -> Result<(ServerList, Vec<ParseIssue>), serde_json::Error> {
+    #[derive(Deserialize)]
+    struct RawList<'a> {
+        #[serde(borrow)]
+        servers: Vec<&'a RawValue>,
+    }
+
+    let raw: RawList = serde_json::from_str(body)?;
+    let mut list = ServerList {
+        servers: Vec::with_capacity(raw.servers.len()),
+    };
+    let mut issues = Vec::new();
+
+    for (index, server) in raw.servers.into_iter().enumerate() {
+        let mut deserializer = serde_json::Deserializer::from_str(server.get());
+        match serde_path_to_error::deserialize::<_, Server>(&mut deserializer) {
+            Ok(server) => list.servers.push(server),
+            Err(e) => {
+                if let Some(server) = without_broken_clients(server.get(), index, e, &mut issues) {
+                    list.servers.push(server);
+                }
+            }
+        }
+    }
+
+    Ok((list, issues))
+}
+
+/// Position of the client a path into a server points at, and the rest of the path
+fn client_in_path(path: &str) -> Option<(usize, &str)> {
+    let (client, rest) = path.strip_prefix("info.clients[")?.split_once(']')?;
+    Some((client.parse().ok()?, rest))
+}
+
+/// Retries a server that failed to parse without the clients the errors point at, until it parses
+/// or an error is outside the client list
+fn without_broken_clients(
+    body: &str,
+    index: usize,
+    mut error: serde_path_to_error::Error<serde_json::Error>,
+    issues: &mut Vec<ParseIssue>,
+) -> Option<Server> {
+    let mut value: serde_json::Value = serde_json::from_str(body).ok()?;
+    let count = value
+        .pointer("/info/clients")
+        .and_then(serde_json::Value::as_array)
+        .map_or(0, Vec::len);
+    let mut positions: Vec<usize> = (0..count).collect();
+    loop {
+        let path = error.path().to_string();
+        let message = error.into_inner().to_string();
+        let target = client_in_path(&path).filter(|(client, _)| *client < positions.len());
+        let (Some((client, rest)), Some(clients)) = (
+            target,
+            value
+                .pointer_mut("/info/clients")
+                .and_then(serde_json::Value::as_array_mut),
+        ) else {
+            issues.push(ParseIssue {
+                index,
+                client: None,
+                path,
+                message,
+            });
+            return None;
+        };
+        clients.remove(client);
+        let original = positions.remove(client);
+        issues.push(ParseIssue {
+            index,
+            client: Some(original),
+            path: format!("info.clients[{}]{}", original, rest),
+            message,
+        });
+        match serde_path_to_error::deserialize::<_, Server>(&value) {
+            Ok(server) => return Some(server),
+            Err(e) => error = e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_clients_only_drop_themselves() {
+        let (list, issues) = parse(
+            r#"{"servers":[
+                {"addresses":["tw-0.6+udp://1.1.1.1:8303"],"info":{"clients":[
+                    {"name":"first"},{"clan":"no name"},{"name":"third","skin":{"color_body":"red"}},{"name":"fourth"}]}},
+                {"info":{"clients":[{"name":"no address"}]}},
+                {"addresses":["tw-0.6+udp://2.2.2.2:8303"],"info":{"name":7,"clients":[{"clan":"no name"}]}}
+            ]}"#,
+        )
+        .unwrap();
+
+        let names: Vec<&str> = list.servers[0]
+            .info
+            .clients
+            .iter()
+            .map(|client| client.name.as_str())
+            .collect();
+        assert_eq!(list.servers.len(), 1);
+        assert_eq!(names, ["first", "fourth"]);
+
+        let issues: Vec<(usize, Option<usize>, &str)> = issues
+            .iter()
+            .map(|issue| (issue.index, issue.client, issue.path.as_str()))
+            .collect();
+        assert_eq!(
+            issues,
+            [
+                (0, Some(1), "info.clients[1]"),
+                (0, Some(2), "info.clients[2].skin.color_body"),
+                (1, None, "."),
+                (2, None, "info.name"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use log::info;
+use rusqlite::{params, Transaction};
+
+use crate::dummies::Dummies;
+use crate::model::ServerList;
+
+// raw rows are kept per minute and pruned after the retention window, the hourly and daily rollups
+// are kept forever. rollup buckets are the first minute of the hour/day, same unit as everything else.
+// people are players and spectators without the likely dummies of someone on the same server. rows
+// from before dummies were tracked have no people count. a server listed twice counts once.
+
+#[derive(Default)]
+struct Count {
+    players: i64,
+    spectators: i64,
+    people: i64,
+}
+
+pub fn update(
+    tx: &Transaction,
+    servers_data: &ServerList,
+    dummies: &Dummies,
+    now: i64,
+    retention: i64,
+) -> rusqlite::Result<()> {
+    let mut servers: HashMap<&str, Count> = HashMap::new();
+    let mut regions: HashMap<&str, Count> = HashMap::new();
+
+    for server in &servers_data.servers {
+        let (Some(address), Some(location)) = (server.address(), server.location.as_deref()) else {
+            continue;
+        };
+        if servers.contains_key(address) {
+            continue;
+        }
+
+        let mut count = Count::default();
+        for client in &server.info.clients {
+            if client.is_player {
+                count.players += 1;
+            } else {
+                count.spectators += 1;
+            }
+            if !dummies.contains(&(address, client.name.as_str())) {
+                count.people += 1;
+            }
+        }
+
+        let region = regions.entry(location).or_default();
+        region.players += count.players;
+        region.spectators += count.spectators;
+        region.people += count.people;
+        servers.insert(address, count);
+    }
+
+    let mut raw_stmt = tx.prepare_cached(
+        "INSERT OR REPLACE INTO population (scope, key, time, players, spectators, people) VALUES (?, ?, ?, ?, ?, ?)",
+    )?;
+    let mut rollup_stmt = tx.prepare_cached(
+        "INSERT INTO population_rollups (scope, key, period, bucket, samples, players_sum, players_max, spectators_sum, spectators_max, people_sum, people_max)
+        VALUES (?1, ?2, ?3, ?4, 1, ?5, ?5, ?6, ?6, ?7, ?7)
+        ON CONFLICT (scope, key, period, bucket) DO UPDATE SET
+            samples = samples + 1,
+            players_sum = players_sum + excluded.players_sum,
+            players_max = max(players_max, excluded.players_max),
+            spectators_sum = spectators_sum + excluded.spectators_sum,
+            spectators_max = max(spectators_max, excluded.spectators_max),
+            people_sum = coalesce(people_sum, 0) + excluded.people_sum,
+            people_max = max(coalesce(people_max, 0), excluded.people_max)",
+    )?;
+
+    let hour = now - now % 60;
+    let day = now - now % (24 * 60);
+
+    for (scope, counts) in [("server", &servers), ("region", &regions)] {
+        for (key, count) in counts {
+            raw_stmt.execute(params![
+                scope,
+                key,
+                now,
+                count.players,
+                count.spectators,
+                count.people
+            ])?;
+            for (period, bucket) in [("hour", hour), ("day", day)] {
+                rollup_stmt.execute(params![
+                    scope,
+                    key,
+                    period,
+                    bucket,
+                    count.players,
+                    count.spectators,
+                    count.people
+                ])?;
+            }
+        }
+    }
+
+    let pruned = tx.execute(
+        "DELETE FROM population WHERE time < ?",
+        params![now - retention],
+    )?;
+
+    info!(
+        "Population: {} servers, {} regions, pruned {} raw rows",
+        servers.len(),
+        regions.len(),
+        pruned
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::{dummies, migrations, model};
+
+    type Row = (String, String, i64, i64, i64);
+
+    fn rows(conn: &Connection, sql: &str) -> Vec<Row> {
+        conn.prepare(sql)
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn counts_servers_once_and_rolls_up() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn, false).unwrap();
+        conn.execute(
+            "INSERT INTO dummy_links (owner, dummy, joins, apart, confidence, first_seen, last_seen) VALUES ('Tee', 'Tee (d)', 3, 0, 0.9, 0, 0)",
+            [],
+        )
+        .unwrap();
+        let list = |spectator: bool| {
+            let body = format!(
+                r#"{{"servers":[
+                    {{"addresses":["tw-0.6+udp://1.1.1.1:8303"],"location":"eu:de","info":{{"clients":[
+                        {{"name":"Tee"}},{{"name":"Tee (d)"}},{{"name":"other","is_player":{}}}]}}}},
+                    {{"addresses":["tw-0.6+udp://1.1.1.1:8303"],"location":"eu:de","info":{{"clients":[{{"name":"Tee"}}]}}}},
+                    {{"addresses":["tw-0.6+udp://2.2.2.2:8303"],"location":"eu:de","info":{{"clients":[{{"name":"alone"}}]}}}}
+                ]}}"#,
+                !spectator
+            );
+            model::parse(&body).unwrap().0
+        };
+
+        let hour = 28_928_160;
+        let tx = conn.transaction().unwrap();
+        for (spectator, time) in [(false, hour), (true, hour + 1), (true, hour + 61)] {
+            // the first tick is past the retention by the last one
+            let servers = list(spectator);
+            let dummies = dummies::likely_dummies(&tx, &servers).unwrap();
+            update(&tx, &servers, &dummies, time, 60).unwrap();
+        }
+        tx.commit().unwrap();
+
+        assert_eq!(
+            rows(
+                &conn,
+                "SELECT scope, key, players, spectators, people FROM population WHERE time = 28928161 ORDER BY scope, key"
+            ),
+            [
+                ("region".into(), "eu:de".into(), 3, 1, 3),
+                ("server".into(), "tw-0.6+udp://1.1.1.1:8303".into(), 2, 1, 2),
+                ("server".into(), "tw-0.6+udp://2.2.2.2:8303".into(), 1, 0, 1),
+            ]
+        );
+        let pruned: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM population WHERE time = ?",
+                params![hour],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(pruned, 0);
+
+        assert_eq!(
+            rows(
+                &conn,
+                "SELECT period, key, samples, players_sum, players_max FROM population_rollups WHERE scope = 'region' ORDER BY period, bucket"
+            ),
+            [
+                ("day".into(), "eu:de".into(), 3, 10, 4),
+                ("hour".into(), "eu:de".into(), 2, 7, 4),
+                ("hour".into(), "eu:de".into(), 1, 3, 3),
+            ]
+        );
+    }
+}
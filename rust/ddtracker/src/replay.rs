@@ -0,0 +1,283 @@
+use cron::Schedule;
+use log::{info, warn};
+use rusqlite::Connection;
+
+use crate::config::Config;
+use crate::ingest::{self, Tracker};
+use crate::{archive, missed_ticks, model, tick_minutes};
+
+// feeds archived ticks through the same trackers as live ticks, one transaction per daily file.
+// meant for filling a new tracker's tables with past data or rebuilding broken ones, with `--reset`
+// clearing the chosen trackers' tables first. most trackers add up counters, so a tracker that
+// already has rows is only replayed with `--reset`, otherwise its ticks would count twice. where the
+// archive skips scheduled ticks the tracker was down, open sessions, listings, stints and teams are
+// closed there like a restart closes them. ticks, gaps and errors of the live run stay as they are.
+// trackers that leave dummies out need the links as they were at each tick, so they bring the
+// dummies tracker along. trackers run in the same order as live. the tracker shouldn't write to the
+// same database while a replay runs.
+
+pub const USAGE: &str =
+    "Usage: ddtracker replay <archive-dir> [--only <tracker,...>] [--reset] [options]";
+
+pub fn run(
+    conn: &mut Connection,
+    dir: &str,
+    trackers: &[Tracker],
+    reset: bool,
+    config: &Config,
+    schedule: &Schedule,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let with_dummies = trackers.iter().any(Tracker::uses_dummies);
+    if with_dummies && !trackers.contains(&Tracker::Dummies) {
+        info!("Replaying dummies too, the links of later ticks would change earlier counts");
+    }
+    let trackers: Vec<Tracker> = Tracker::ALL
+        .into_iter()
+        .filter(|tracker| {
+            trackers.contains(tracker) || (with_dummies && *tracker == Tracker::Dummies)
+        })
+        .collect();
+    let trackers = trackers.as_slice();
+    let names: Vec<&str> = trackers.iter().map(Tracker::name).collect();
+    info!("Replaying {} into {}", dir, names.join(", "));
+
+    if !reset {
+        for tracker in trackers {
+            for table in tracker.tables() {
+                let filled: bool = conn.query_row(
+                    &format!("SELECT EXISTS (SELECT 1 FROM {})", table),
+                    [],
+                    |row| row.get(0),
+                )?;
+                if filled {
+                    return Err(format!(
+                        "Tracker {} already has rows in {}, replaying would count its ticks twice, use --reset to rebuild it",
+                        tracker.name(),
+                        table
+                    )
+                    .into());
+                }
+            }
+        }
+    }
+
+    if reset && !config.dry_run {
+        let tx = conn.transaction()?;
+        for table in trackers.iter().flat_map(Tracker::tables) {
+            let deleted = tx.execute(&format!("DELETE FROM {}", table), [])?;
+            info!("Cleared {} rows from {}", deleted, table);
+        }
+        tx.commit()?;
+    }
+
+    let mut total = 0;
+    let mut last = None;
+    for path in archive::files(dir)? {
+        let tx = conn.transaction()?;
+        let mut ticks = 0;
+        for record in archive::read(&path)? {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!(
+                        "Stopped reading {} at a broken record: {}",
+                        path.display(),
+                        e
+                    );
+                    break;
+                }
+            };
+            let (servers, issues) = match model::parse(record.body.get()) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    warn!("Skipped tick {}: {}", record.time, e);
+                    continue;
+                }
+            };
+            if !issues.is_empty() {
+                let malformed = issues.iter().filter(|issue| issue.client.is_none()).count();
+                warn!(
+                    "Skipped {} malformed server entries and {} malformed clients in tick {}",
+                    malformed,
+                    issues.len() - malformed,
+                    record.time
+                );
+            }
+            if let Some((start, end)) =
+                last.and_then(|last| missed_ticks(schedule, last, record.time))
+            {
+                info!(
+                    "Archive has no ticks from {} to {}, closing what was open",
+                    start, end
+                );
+                for tracker in trackers {
+                    tracker.close_all(&tx)?;
+                }
+            }
+            let minutes = tick_minutes(schedule, last, record.time);
+            ingest::run(&tx, &servers, record.time, minutes, config, trackers)?;
+            last = Some(record.time);
+            ticks += 1;
+        }
+        if config.dry_run {
+            tx.rollback()?;
+        } else {
+            tx.commit()?;
+        }
+        info!("Replayed {} ticks from {}", ticks, path.display());
+        total += ticks;
+    }
+
+    // the archive ends wherever it ends, nobody is online after it
+    if !config.dry_run {
+        for tracker in trackers {
+            tracker.close_all(conn)?;
+        }
+    }
+
+    info!("Replay completed, {} ticks", total);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::archive::Archive;
+    use crate::fetch::Fetched;
+    use crate::migrations;
+
+    /// An archive in a fresh temporary directory with the given ticks, each a list of names online
+    /// on one server
+    fn archive(name: &str, ticks: &[(i64, &[&str])]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ddtracker-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let archive = Archive::new(dir.to_str().unwrap()).unwrap();
+        for (time, names) in ticks {
+            let clients: Vec<String> = names
+                .iter()
+                .map(|name| format!(r#"{{"name":"{}"}}"#, name))
+                .collect();
+            let body = format!(
+                r#"{{"servers":[{{"addresses":["tw-0.6+udp://1.1.1.1:8303"],"location":"eu:de","info":{{"map":{{"name":"Kobra"}},"clients":[{}]}}}}]}}"#,
+                clients.join(",")
+            );
+            let fetched = Fetched {
+                servers: model::parse(&body).unwrap().0,
+                malformed: 0,
+                sources: Vec::new(),
+                raw: Some(body),
+            };
+            archive.store(*time, &fetched).unwrap();
+        }
+        dir
+    }
+
+    fn database() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn, false).unwrap();
+        conn
+    }
+
+    fn sessions(conn: &Connection) -> Vec<(String, i64, i64, bool)> {
+        conn.prepare(
+            "SELECT name, start_time, end_time, online FROM sessions ORDER BY name, start_time",
+        )
+        .unwrap()
+        .query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })
+        .unwrap()
+        .collect::<rusqlite::Result<_>>()
+        .unwrap()
+    }
+
+    #[test]
+    fn closes_sessions_at_gaps_and_rebuilds_only_with_reset() {
+        let start = 28_928_160;
+        let dir = archive(
+            "gaps",
+            &[
+                (start, &["Tee"]),
+                (start + 1, &["Tee", "other"]),
+                // the tracker was down for two ticks
+                (start + 4, &["Tee"]),
+                (start + 5, &["Tee"]),
+            ],
+        );
+        let dir = dir.to_str().unwrap();
+        let schedule = Schedule::from_str("0 * * * * *").unwrap();
+        let config = Config::default();
+        let mut conn = database();
+
+        run(
+            &mut conn,
+            dir,
+            &[Tracker::Sessions],
+            false,
+            &config,
+            &schedule,
+        )
+        .unwrap();
+        let replayed = vec![
+            ("Tee".to_string(), start, start + 1, false),
+            ("Tee".to_string(), start + 4, start + 5, false),
+            ("other".to_string(), start + 1, start + 1, false),
+        ];
+        assert_eq!(sessions(&conn), replayed);
+
+        assert!(run(
+            &mut conn,
+            dir,
+            &[Tracker::Sessions],
+            false,
+            &config,
+            &schedule
+        )
+        .is_err());
+        assert_eq!(sessions(&conn), replayed);
+        run(
+            &mut conn,
+            dir,
+            &[Tracker::Sessions],
+            true,
+            &config,
+            &schedule,
+        )
+        .unwrap();
+        assert_eq!(sessions(&conn), replayed);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn trackers_leaving_dummies_out_bring_dummies_along() {
+        let start = 28_928_160;
+        let dir = archive("dummies", &[(start, &["Tee"])]);
+        let dir = dir.to_str().unwrap();
+        let schedule = Schedule::from_str("0 * * * * *").unwrap();
+        let config = Config::default();
+        let mut conn = database();
+        conn.execute(
+            "INSERT INTO dummy_links (owner, dummy, joins, apart, confidence, first_seen, last_seen) VALUES ('Tee', 'Tee (d)', 3, 0, 0.9, 0, 0)",
+            [],
+        )
+        .unwrap();
+
+        // the links learned live are in the way
+        assert!(run(&mut conn, dir, &[Tracker::Maps], false, &config, &schedule).is_err());
+        run(&mut conn, dir, &[Tracker::Maps], true, &config, &schedule).unwrap();
+        let (links, servers): (i64, i64) = conn
+            .query_row(
+                "SELECT (SELECT COUNT(*) FROM dummy_links), (SELECT COUNT(*) FROM dummy_servers)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((links, servers), (0, 1));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::{HashMap, HashSet};
+
+use log::info;
+use rusqlite::{params, Connection, Transaction};
+
+use crate::model::ServerList;
+
+// a stint is one map staying up on one server, with the players (not spectators) it had when it
+// started, when it was last seen and on average. `end_time` is the last minute the map was seen,
+// `online` is cleared when the map changes or the server drops off the list. a switch between two
+// ticks in a row is logged in `map_changes`, a server coming back with another map only starts a
+// new stint since nobody saw the switch.
+
+pub fn close_all(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.execute("UPDATE map_stints SET online = 0 WHERE online = 1", [])
+}
+
+struct Stint {
+    id: i64,
+    map: String,
+    last_players: i64,
+}
+
+pub fn update(tx: &Transaction, servers_data: &ServerList, now: i64) -> rusqlite::Result<()> {
+    let mut open: HashMap<String, Stint> = HashMap::new();
+    {
+        let mut stmt = tx.prepare_cached(
+            "SELECT id, address, map, last_players FROM map_stints WHERE online = 1",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            open.insert(
+                row.get(1)?,
+                Stint {
+                    id: row.get(0)?,
+                    map: row.get(2)?,
+                    last_players: row.get(3)?,
+                },
+            );
+        }
+    }
+
+    let mut extend_stmt = tx.prepare_cached(
+        "UPDATE map_stints SET end_time = ?1, ticks = ticks + 1, last_players = ?2, players_sum = players_sum + ?2, players_max = MAX(players_max, ?2) WHERE id = ?3",
+    )?;
+    let mut close_stmt = tx.prepare_cached("UPDATE map_stints SET online = 0 WHERE id = ?")?;
+    let mut insert_stmt = tx.prepare_cached(
+        "INSERT INTO map_stints (address, map, start_time, end_time, ticks, first_players, last_players, players_sum, players_max, online)
+        VALUES (?1, ?2, ?3, ?3, 1, ?4, ?4, ?4, ?4, 1)",
+    )?;
+    let mut change_stmt = tx.prepare_cached(
+        "INSERT INTO map_changes (address, time, previous_map, map, previous_players, players) VALUES (?, ?, ?, ?, ?, ?)",
+    )?;
+
+    let mut seen: HashSet<&str> = HashSet::new();
+    let (mut started, mut switched) = (0, 0);
+    for server in &servers_data.servers {
+        let Some(address) = server.address() else {
+            continue;
+        };
+        let map = server.info.map.name.as_str();
+        if map.is_empty() || !seen.insert(address) {
+            continue;
+        }
+        let players = server
+            .info
+            .clients
+            .iter()
+            .filter(|client| client.is_player)
+            .count() as i64;
+
+        match open.remove(address) {
+            Some(stint) if stint.map == map => {
+                extend_stmt.execute(params![now, players, stint.id])?;
+            }
+            previous => {
+                if let Some(stint) = previous {
+                    close_stmt.execute(params![stint.id])?;
+                    change_stmt.execute(params![
+                        address,
+                        now,
+                        stint.map,
+                        map,
+                        stint.last_players,
+                        players
+                    ])?;
+                    switched += 1;
+                }
+                insert_stmt.execute(params![address, map, now, players])?;
+                started += 1;
+            }
+        }
+    }
+
+    // servers left have dropped off the list
+    for stint in open.values() {
+        close_stmt.execute(params![stint.id])?;
+    }
+
+    info!(
+        "Rotations: {} map changes, {} stints started, {} closed",
+        switched,
+        started,
+        open.len() + switched
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::{migrations, model};
+
+    /// One server with the map and that many players plus a spectator, or no server at all
+    fn tick(conn: &mut Connection, map: Option<(&str, usize)>, now: i64) {
+        let servers = map.map_or(String::new(), |(map, players)| {
+            let mut clients: Vec<String> = (0..players)
+                .map(|index| format!(r#"{{"name":"Tee{}"}}"#, index))
+                .collect();
+            clients.push(r#"{"name":"Spec","is_player":false}"#.to_string());
+            format!(
+                r#"{{"addresses":["tw-0.6+udp://1.1.1.1:8303"],"location":"eu:de","info":{{"map":{{"name":"{}"}},"clients":[{}]}}}}"#,
+                map,
+                clients.join(",")
+            )
+        });
+        let (servers, _) = model::parse(&format!(r#"{{"servers":[{}]}}"#, servers)).unwrap();
+        let tx = conn.transaction().unwrap();
+        update(&tx, &servers, now).unwrap();
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn switches_are_logged_and_servers_coming_back_only_start_a_stint() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn, false).unwrap();
+
+        tick(&mut conn, Some(("Kobra", 2)), 100);
+        tick(&mut conn, Some(("Kobra", 4)), 101);
+        tick(&mut conn, Some(("Linear", 1)), 102);
+        // the server drops off the list and comes back with another map
+        tick(&mut conn, None, 103);
+        tick(&mut conn, Some(("Epix", 3)), 104);
+
+        let changes: Vec<(i64, String, String, i64, i64)> = conn
+            .prepare("SELECT time, previous_map, map, previous_players, players FROM map_changes")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            changes,
+            vec![(102, "Kobra".to_string(), "Linear".to_string(), 4, 1)]
+        );
+
+        type StintRow = (String, i64, i64, i64, i64, i64, i64, bool);
+        let stints: Vec<StintRow> = conn
+            .prepare("SELECT map, start_time, end_time, ticks, first_players, last_players, players_max, online FROM map_stints ORDER BY id")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                    row.get(7)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            stints,
+            vec![
+                ("Kobra".to_string(), 100, 101, 2, 2, 4, 4, false),
+                ("Linear".to_string(), 102, 102, 1, 1, 1, 1, false),
+                ("Epix".to_string(), 104, 104, 1, 3, 3, 3, true),
+            ]
+        );
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::{HashMap, HashSet};
+
+use log::info;
+use rusqlite::{params, Connection, Transaction};
+
+use crate::model::ServerList;
+
+// `servers` holds the latest metadata of every server ever listed, keyed by its first address.
+// renames and gametype switches are kept in `server_changes`. `server_listings` has one row per
+// stretch of ticks a server stayed on the list, like sessions do for players, so uptime is the share
+// of recorded ticks that fall into a server's listings.
+
+pub fn close_all(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.execute("UPDATE server_listings SET online = 0 WHERE online = 1", [])
+}
+
+pub fn update(tx: &Transaction, servers_data: &ServerList, now: i64) -> rusqlite::Result<()> {
+    let mut known: HashMap<String, (String, String)> = HashMap::new();
+    {
+        let mut stmt = tx.prepare_cached("SELECT address, name, game_type FROM servers")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            known.insert(row.get(0)?, (row.get(1)?, row.get(2)?));
+        }
+    }
+    let mut open: HashMap<String, i64> = HashMap::new();
+    {
+        let mut stmt =
+            tx.prepare_cached("SELECT id, address FROM server_listings WHERE online = 1")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            open.insert(row.get(1)?, row.get(0)?);
+        }
+    }
+
+    let mut upsert_stmt = tx.prepare_cached(
+        "INSERT INTO servers (address, addresses, region, name, game_type, version, passworded, max_clients, max_players, first_seen, last_seen)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?10)
+        ON CONFLICT (address) DO UPDATE SET addresses = excluded.addresses, region = excluded.region, name = excluded.name,
+        game_type = excluded.game_type, version = excluded.version, passworded = excluded.passworded,
+        max_clients = excluded.max_clients, max_players = excluded.max_players, last_seen = excluded.last_seen",
+    )?;
+    let mut change_stmt = tx.prepare_cached(
+        "INSERT INTO server_changes (address, time, field, old_value, new_value) VALUES (?, ?, ?, ?, ?)",
+    )?;
+    let mut extend_stmt =
+        tx.prepare_cached("UPDATE server_listings SET end_time = ? WHERE id = ?")?;
+    let mut close_stmt = tx.prepare_cached("UPDATE server_listings SET online = 0 WHERE id = ?")?;
+    let mut insert_stmt = tx.prepare_cached(
+        "INSERT INTO server_listings (address, start_time, end_time, online) VALUES (?, ?, ?, 1)",
+    )?;
+
+    let mut seen: HashSet<&str> = HashSet::new();
+    let (mut appeared, mut new, mut changes) = (0, 0, 0);
+    for server in &servers_data.servers {
+        let Some(address) = server.address() else {
+            continue;
+        };
+        if !seen.insert(address) {
+            continue;
+        }
+        let info = &server.info;
+
+        match known.get(address) {
+            Some((name, game_type)) => {
+                for (field, old, current) in [
+                    ("name", name, &info.name),
+                    ("game_type", game_type, &info.game_type),
+                ] {
+                    if old != current {
+                        change_stmt.execute(params![address, now, field, old, current])?;
+                        changes += 1;
+                    }
+                }
+            }
+            None => new += 1,
+        }
+        upsert_stmt.execute(params![
+            address,
+            serde_json::to_string(&server.addresses).unwrap_or_default(),
+            server.location,
+            info.name,
+            info.game_type,
+            info.version,
+            info.passworded,
+            info.max_clients,
+            info.max_players,
+            now
+        ])?;
+
+        match open.remove(address) {
+            Some(id) => {
+                extend_stmt.execute(params![now, id])?;
+            }
+            None => {
+                insert_stmt.execute(params![address, now, now])?;
+                appeared += 1;
+            }
+        }
+    }
+
+    // whatever is left dropped off the list
+    let dropped = open.len();
+    for id in open.values() {
+        close_stmt.execute(params![id])?;
+    }
+
+    info!(
+        "Servers: {} listed, {} new, {} appeared, {} dropped, {} renames or gametype changes",
+        seen.len(),
+        new,
+        appeared,
+        dropped,
+        changes
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::{migrations, model};
+
+    fn tick(conn: &mut Connection, servers: &[(&str, &str)], now: i64) {
+        let servers: Vec<String> = servers
+            .iter()
+            .map(|(address, name)| {
+                format!(
+                    r#"{{"addresses":["{}"],"location":"eu:de","info":{{"name":"{}","game_type":"DDraceNetwork","clients":[]}}}}"#,
+                    address, name
+                )
+            })
+            .collect();
+        let (servers, _) =
+            model::parse(&format!(r#"{{"servers":[{}]}}"#, servers.join(","))).unwrap();
+        let tx = conn.transaction().unwrap();
+        update(&tx, &servers, now).unwrap();
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn renames_and_listings_are_kept() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn, false).unwrap();
+        let a = "tw-0.6+udp://1.1.1.1:8303";
+        let b = "tw-0.6+udp://2.2.2.2:8303";
+
+        // a server listed twice is one listing
+        tick(&mut conn, &[(a, "One"), (a, "One"), (b, "Two")], 100);
+        tick(&mut conn, &[(a, "One renamed")], 101);
+        tick(&mut conn, &[(a, "One renamed"), (b, "Two")], 102);
+        // the tracker was down
+        assert_eq!(close_all(&conn).unwrap(), 2);
+        tick(&mut conn, &[(a, "One renamed")], 110);
+
+        let changes: Vec<(String, i64, String, String)> = conn
+            .prepare(
+                "SELECT field, time, old_value, new_value FROM server_changes WHERE address = ?",
+            )
+            .unwrap()
+            .query_map([a], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            changes,
+            vec![(
+                "name".to_string(),
+                101,
+                "One".to_string(),
+                "One renamed".to_string()
+            )]
+        );
+
+        let listings: Vec<(String, i64, i64, bool)> = conn
+            .prepare("SELECT address, start_time, end_time, online FROM server_listings ORDER BY address, start_time")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            listings,
+            vec![
+                (a.to_string(), 100, 102, false),
+                (a.to_string(), 110, 110, true),
+                (b.to_string(), 100, 100, false),
+                (b.to_string(), 102, 102, false),
+            ]
+        );
+
+        let server: (String, i64, i64) = conn
+            .query_row(
+                "SELECT name, first_seen, last_seen FROM servers WHERE address = ?",
+                [a],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(server, ("One renamed".to_string(), 100, 110));
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::{HashMap, HashSet};
+
+use log::info;
+use rusqlite::{params, Connection, Transaction};
+
+use crate::model::ServerList;
+
+// a session is one player staying on one server and map. `end_time` is the last minute the player
+// was seen, `online` is cleared once the player is gone.
+
+pub fn close_all(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.execute("UPDATE sessions SET online = 0 WHERE online = 1", [])
+}
+
+pub fn update(tx: &Transaction, servers_data: &ServerList, now: i64) -> rusqlite::Result<()> {
+    let mut open: HashMap<(String, String), (i64, String)> = HashMap::new();
+    {
+        let mut stmt =
+            tx.prepare_cached("SELECT id, name, address, map FROM sessions WHERE online = 1")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                (row.get::<_, String>(1)?, row.get::<_, String>(2)?),
+                (row.get::<_, i64>(0)?, row.get::<_, String>(3)?),
+            ))
+        })?;
+        for row in rows {
+            let (key, value) = row?;
+            open.insert(key, value);
+        }
+    }
+
+    let mut extend_stmt = tx.prepare_cached("UPDATE sessions SET end_time = ? WHERE id = ?")?;
+    let mut close_stmt = tx.prepare_cached("UPDATE sessions SET online = 0 WHERE id = ?")?;
+    let mut insert_stmt = tx.prepare_cached(
+        "INSERT INTO sessions (name, address, map, region, start_time, end_time, online) VALUES (?, ?, ?, ?, ?, ?, 1)",
+    )?;
+
+    let mut seen: HashSet<(String, String)> = HashSet::new();
+    let mut started = 0;
+    let mut closed = 0;
+
+    for server in &servers_data.servers {
+        let (Some(address), Some(location)) = (server.address(), server.location.as_deref()) else {
+            continue;
+        };
+        let map = server.info.map.name.as_str();
+
+        for client in &server.info.clients {
+            let name = client.name.as_str();
+
+            let key = (name.to_string(), address.to_string());
+            if !seen.insert(key.clone()) {
+                // same name twice on one server, the first one already covers it
+                continue;
+            }
+
+            match open.remove(&key) {
+                Some((id, session_map)) if session_map == map => {
+                    extend_stmt.execute(params![now, id])?;
+                }
+                previous => {
+                    if let Some((id, _)) = previous {
+                        // map changed, split the session
+                        close_stmt.execute(params![id])?;
+                        closed += 1;
+                    }
+                    insert_stmt.execute(params![name, address, map, location, now, now])?;
+                    started += 1;
+                }
+            }
+        }
+    }
+
+    // whoever is left has disconnected or their server dropped off the list
+    for (id, _) in open.values() {
+        close_stmt.execute(params![id])?;
+        closed += 1;
+    }
+
+    info!(
+        "Sessions: {} started, {} closed, {} online",
+        started,
+        closed,
+        seen.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::{migrations, model};
+
+    fn list(map: &str, names: &[&str]) -> ServerList {
+        let clients: Vec<String> = names
+            .iter()
+            .map(|name| format!(r#"{{"name":"{}"}}"#, name))
+            .collect();
+        model::parse(&format!(
+            r#"{{"servers":[{{"addresses":["tw-0.6+udp://1.1.1.1:8303"],"location":"eu:de","info":{{"map":{{"name":"{}"}},"clients":[{}]}}}}]}}"#,
+            map,
+            clients.join(",")
+        ))
+        .unwrap()
+        .0
+    }
+
+    fn sessions(conn: &Connection) -> Vec<(String, String, i64, i64, bool)> {
+        conn.prepare("SELECT name, map, start_time, end_time, online FROM sessions ORDER BY id")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn tick(conn: &mut Connection, servers: &ServerList, now: i64) {
+        let tx = conn.transaction().unwrap();
+        update(&tx, servers, now).unwrap();
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn sessions_split_on_map_changes_and_reopen_after_a_gap() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn, false).unwrap();
+
+        // a name twice on one server is one session
+        tick(&mut conn, &list("Kobra", &["Tee", "Tee", "Other"]), 100);
+        tick(&mut conn, &list("Kobra", &["Tee"]), 101);
+        tick(&mut conn, &list("Linear", &["Tee"]), 102);
+        let session = |map: &str, start, end, online| {
+            ("Tee".to_string(), map.to_string(), start, end, online)
+        };
+        let other = ("Other".to_string(), "Kobra".to_string(), 100, 100, false);
+        assert_eq!(
+            sessions(&conn),
+            vec![
+                session("Kobra", 100, 101, false),
+                other.clone(),
+                session("Linear", 102, 102, true),
+            ]
+        );
+
+        // the tracker was down, the open session ends at the last tick it saw
+        assert_eq!(close_all(&conn).unwrap(), 1);
+        tick(&mut conn, &list("Linear", &["Tee"]), 130);
+        assert_eq!(
+            sessions(&conn),
+            vec![
+                session("Kobra", 100, 101, false),
+                other,
+                session("Linear", 102, 102, false),
+                session("Linear", 130, 130, true),
+            ]
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashSet;
+
+use log::info;
+use rusqlite::{params, Transaction};
+
+use crate::dummies::Dummies;
+use crate::model::ServerList;
+
+// daily counts of distinct players per skin name and region, and per skin name and color bucket.
+// a player counts once a day however long they play, the players already counted today are kept in
+// `skin_players` and `skin_color_players` and dropped once the day is over. colors are ddnet's packed
+// hsl (`h << 16 | s << 8 | l`), grouped into 16 hues, 4 saturations and 4 lightnesses. a bucket is
+// stored as the packed color of its center, so it renders like any other skin color. players without
+// custom colors only count towards popularity. likely dummies don't count, they wear their owner's skin.
+
+const HUE_STEP: i64 = 16;
+const SATURATION_STEP: i64 = 64;
+const LIGHTNESS_STEP: i64 = 64;
+
+/// Packed color of the center of the bucket a packed hsl color falls into
+fn color_bucket(color: i64) -> i64 {
+    let center = |value: i64, step: i64| value - value % step + step / 2;
+    let hue = center((color >> 16) & 0xff, HUE_STEP);
+    let saturation = center((color >> 8) & 0xff, SATURATION_STEP);
+    let lightness = center(color & 0xff, LIGHTNESS_STEP);
+    hue << 16 | saturation << 8 | lightness
+}
+
+pub fn update(
+    tx: &Transaction,
+    servers_data: &ServerList,
+    dummies: &Dummies,
+    now: i64,
+) -> rusqlite::Result<()> {
+    let mut players: HashSet<(&str, &str, &str)> = HashSet::new();
+    let mut colors: HashSet<(&str, &str, i64, &str)> = HashSet::new();
+
+    for server in &servers_data.servers {
+        let (Some(address), Some(location)) = (server.address(), server.location.as_deref()) else {
+            continue;
+        };
+        for client in &server.info.clients {
+            if dummies.contains(&(address, client.name.as_str())) {
+                continue;
+            }
+            let Some(skin) = &client.skin else {
+                continue;
+            };
+            let Some(skin_name) = skin.name.as_deref() else {
+                continue;
+            };
+            let name = client.name.as_str();
+            players.insert((location, skin_name, name));
+            if let Some(body) = skin.color_body {
+                colors.insert((skin_name, "body", color_bucket(body), name));
+            }
+            if let Some(feet) = skin.color_feet {
+                colors.insert((skin_name, "feet", color_bucket(feet), name));
+            }
+        }
+    }
+
+    let day = now - now % (24 * 60);
+    tx.prepare_cached("DELETE FROM skin_players WHERE day < ?")?
+        .execute(params![day])?;
+    tx.prepare_cached("DELETE FROM skin_color_players WHERE day < ?")?
+        .execute(params![day])?;
+
+    let mut seen_stmt = tx.prepare_cached(
+        "INSERT OR IGNORE INTO skin_players (day, region, skin, name) VALUES (?, ?, ?, ?)",
+    )?;
+    let mut count_stmt = tx.prepare_cached(
+        "INSERT INTO skin_popularity (day, region, skin, players) VALUES (?, ?, ?, 1)
+        ON CONFLICT (day, region, skin) DO UPDATE SET players = players + 1",
+    )?;
+    let mut counted = 0;
+    for (region, skin, name) in &players {
+        if seen_stmt.execute(params![day, region, skin, name])? > 0 {
+            count_stmt.execute(params![day, region, skin])?;
+            counted += 1;
+        }
+    }
+
+    let mut color_seen_stmt = tx.prepare_cached(
+        "INSERT OR IGNORE INTO skin_color_players (day, skin, part, color, name) VALUES (?, ?, ?, ?, ?)",
+    )?;
+    let mut color_count_stmt = tx.prepare_cached(
+        "INSERT INTO skin_colors (skin, part, day, color, players) VALUES (?, ?, ?, ?, 1)
+        ON CONFLICT (skin, part, day, color) DO UPDATE SET players = players + 1",
+    )?;
+    for (skin, part, color, name) in &colors {
+        if color_seen_stmt.execute(params![day, skin, part, color, name])? > 0 {
+            color_count_stmt.execute(params![skin, part, day, color])?;
+        }
+    }
+
+    info!(
+        "Skin stats: {} players by skin and region, {} counted for the first time today",
+        players.len(),
+        counted
+    );
+    Ok(())
+}
@@ -0,0 +1,591 @@
+use std::collections::HashMap;
+
+use log::{debug, info};
+use rusqlite::{params, OptionalExtension, Transaction};
+
+use crate::model::{ServerList, Skin};
+
+// a player's current skin in a region only changes once the previous one has been unseen for
+// `change_delay` minutes, so two players sharing a name don't flip it every tick. the tick's clients
+// are grouped by name and region in one scan and the stored state of all of them is loaded in bulk
+// through a temp table. each group then goes through the same two steps the tracker always took:
+// first every sighting of the current skin refreshes it, then the sightings in order may replace it.
+// skins are stored once in `skins` and referenced by id, so equal skins compare by id. names and
+// colors can be missing, lookups match them with `IS` since the unique index lets nulls repeat.
+
+/// A skin change accepted this tick, first sightings are not changes
+pub struct SkinChange {
+    pub name: String,
+    pub region: String,
+    pub skin: String,
+}
+
+struct Stored {
+    /// `None` for a skin that didn't survive the move into the skins table
+    skin: Option<i64>,
+    time: i64,
+}
+
+/// Latest skin_history row of a player
+struct Latest {
+    /// `None` for a row inserted this tick
+    id: Option<i64>,
+    skin: Option<i64>,
+    first_seen: i64,
+}
+
+#[derive(Default)]
+struct Group<'a> {
+    /// Ids of the skins in the order they were seen
+    skins: Vec<i64>,
+    stored: Option<Stored>,
+    latest: Option<Latest>,
+    name: &'a str,
+    region: &'a str,
+}
+
+/// What a group's tick changes, applied to the database afterwards
+#[derive(Default)]
+struct Outcome {
+    /// New current skin and time, or just a new time
+    current: Option<(Option<i64>, i64)>,
+    touch_latest: Option<i64>,
+    inserts: Vec<i64>,
+    changes: Vec<i64>,
+}
+
+impl Group<'_> {
+    // extend the latest history entry if it still holds the same skin, otherwise start a new one
+    fn record_history(&mut self, outcome: &mut Outcome, skin: i64, now: i64) {
+        match &self.latest {
+            Some(latest) if latest.skin == Some(skin) => {
+                if let Some(id) = latest.id {
+                    outcome.touch_latest = Some(id);
+                }
+            }
+            _ => {
+                outcome.inserts.push(skin);
+                let newest = self
+                    .latest
+                    .as_ref()
+                    .is_none_or(|latest| now >= latest.first_seen);
+                if newest {
+                    self.latest = Some(Latest {
+                        id: None,
+                        skin: Some(skin),
+                        first_seen: now,
+                    });
+                }
+            }
+        }
+    }
+
+    fn decide(&mut self, now: i64, change_delay: i64) -> Outcome {
+        let mut outcome = Outcome::default();
+        let skins = std::mem::take(&mut self.skins);
+
+        // first pass, the same skin is still in use, update the skin time
+        for &skin in &skins {
+            if self
+                .stored
+                .as_ref()
+                .is_some_and(|stored| stored.skin == Some(skin))
+            {
+                if let Some(stored) = &mut self.stored {
+                    stored.time = now;
+                }
+                outcome.current.get_or_insert((None, now));
+                self.record_history(&mut outcome, skin, now);
+                debug!("Updated skin time for {} in {}", self.name, self.region);
+            }
+        }
+
+        // second pass, update the skin if the current skin has not been seen for `change_delay` minutes
+        for &skin in &skins {
+            match &self.stored {
+                Some(stored) if stored.skin != Some(skin) && stored.time + change_delay < now => {
+                    outcome.changes.push(skin);
+                    debug!("Updated skin for {} in {}", self.name, self.region);
+                }
+                Some(_) => continue,
+                None => debug!("Inserted skin for {} in {}", self.name, self.region),
+            }
+            self.stored = Some(Stored {
+                skin: Some(skin),
+                time: now,
+            });
+            outcome.current = Some((Some(skin), now));
+            self.record_history(&mut outcome, skin, now);
+        }
+
+        outcome
+    }
+}
+
+/// Name, body color and feet color, the identity of a skin
+type SkinKey<'a> = (Option<&'a str>, Option<i64>, Option<i64>);
+
+/// Ids of the skins seen this tick
+#[derive(Default)]
+struct SkinIds<'a> {
+    ids: HashMap<SkinKey<'a>, i64>,
+    skins: HashMap<i64, &'a Skin>,
+}
+
+impl<'a> SkinIds<'a> {
+    fn get(&mut self, tx: &Transaction, skin: &'a Skin) -> rusqlite::Result<i64> {
+        let key = (skin.name.as_deref(), skin.color_body, skin.color_feet);
+        if let Some(&id) = self.ids.get(&key) {
+            return Ok(id);
+        }
+        let id = find_or_insert(tx, skin)?;
+        self.ids.insert(key, id);
+        self.skins.insert(id, skin);
+        Ok(id)
+    }
+
+    /// Stored form of a skin, `{"n":..,"b":..,"f":..}`
+    fn data(&self, id: i64) -> String {
+        self.skins
+            .get(&id)
+            .map(|skin| skin.data())
+            .unwrap_or_default()
+    }
+}
+
+/// Id of a skin, adding it to the skins table when it's new
+fn find_or_insert(tx: &Transaction, skin: &Skin) -> rusqlite::Result<i64> {
+    let key = params![skin.name, skin.color_body, skin.color_feet];
+    let found = tx
+        .prepare_cached("SELECT id FROM skins WHERE name IS ? AND body IS ? AND feet IS ?")?
+        .query_row(key, |row| row.get(0))
+        .optional()?;
+    if let Some(id) = found {
+        return Ok(id);
+    }
+    tx.prepare_cached("INSERT INTO skins (name, body, feet) VALUES (?, ?, ?)")?
+        .execute(key)?;
+    Ok(tx.last_insert_rowid())
+}
+
+fn load_state(tx: &Transaction, groups: &mut [Group]) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TEMP TABLE IF NOT EXISTS tick_clients (position INTEGER PRIMARY KEY, name TEXT, region TEXT);
+        DELETE FROM temp.tick_clients;",
+    )?;
+    let mut insert_stmt = tx.prepare_cached(
+        "INSERT INTO temp.tick_clients (position, name, region) VALUES (?, ?, ?)",
+    )?;
+    for (position, group) in groups.iter().enumerate() {
+        insert_stmt.execute(params![position, group.name, group.region])?;
+    }
+
+    let mut stored_stmt = tx.prepare_cached(
+        "SELECT t.position, c.current_skin_id, c.current_skin_time FROM clients c JOIN temp.tick_clients t ON c.name = t.name AND c.region = t.region",
+    )?;
+    let mut rows = stored_stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        if let Some(group) = groups.get_mut(row.get::<_, usize>(0)?) {
+            group.stored = Some(Stored {
+                skin: row.get(1)?,
+                time: row.get(2)?,
+            });
+        }
+    }
+
+    let mut latest_stmt = tx.prepare_cached(
+        "SELECT position, id, skin_id, first_seen FROM (SELECT t.position, h.id, h.skin_id, h.first_seen, ROW_NUMBER() OVER (PARTITION BY t.position ORDER BY h.first_seen DESC, h.id DESC) AS latest FROM skin_history h JOIN temp.tick_clients t ON h.name = t.name AND h.region = t.region) WHERE latest = 1",
+    )?;
+    let mut rows = latest_stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        if let Some(group) = groups.get_mut(row.get::<_, usize>(0)?) {
+            group.latest = Some(Latest {
+                id: Some(row.get(1)?),
+                skin: row.get(2)?,
+                first_seen: row.get(3)?,
+            });
+        }
+    }
+    Ok(())
+}
+
+pub fn update(
+    tx: &Transaction,
+    servers_data: &ServerList,
+    now: i64,
+    change_delay: i64,
+) -> rusqlite::Result<Vec<SkinChange>> {
+    // every client that has a skin, by name and the region of its server, in order of appearance
+    let mut positions: HashMap<(&str, &str), usize> = HashMap::new();
+    let mut ids = SkinIds::default();
+    let mut groups: Vec<Group> = Vec::new();
+    for server in &servers_data.servers {
+        let Some(location) = server.location.as_deref() else {
+            continue;
+        };
+        for client in &server.info.clients {
+            let Some(skin) = &client.skin else {
+                continue;
+            };
+            let position = *positions
+                .entry((client.name.as_str(), location))
+                .or_insert_with(|| {
+                    groups.push(Group {
+                        name: client.name.as_str(),
+                        region: location,
+                        ..Default::default()
+                    });
+                    groups.len() - 1
+                });
+            groups[position].skins.push(ids.get(tx, skin)?);
+        }
+    }
+
+    load_state(tx, &mut groups)?;
+
+    let mut update_stmt = tx.prepare_cached(
+        "INSERT OR REPLACE INTO clients (name, region, current_skin_id, current_skin_time) VALUES (?, ?, ?, ?)",
+    )?;
+    let mut time_stmt = tx
+        .prepare_cached("UPDATE clients SET current_skin_time = ? WHERE name = ? AND region = ?")?;
+    let mut touch_stmt = tx.prepare_cached("UPDATE skin_history SET last_seen = ? WHERE id = ?")?;
+    let mut history_stmt = tx.prepare_cached(
+        "INSERT INTO skin_history (name, region, skin_id, first_seen, last_seen) VALUES (?, ?, ?, ?, ?)",
+    )?;
+
+    let mut changes = Vec::new();
+    let (mut refreshed, mut inserted) = (0, 0);
+    for group in &mut groups {
+        let existed = group.stored.is_some();
+        let outcome = group.decide(now, change_delay);
+        let (name, region) = (group.name, group.region);
+
+        match outcome.current {
+            Some((Some(skin), time)) => {
+                update_stmt.execute(params![name, region, skin, time])?;
+                if !existed {
+                    inserted += 1;
+                }
+            }
+            Some((None, time)) => {
+                time_stmt.execute(params![time, name, region])?;
+                refreshed += 1;
+            }
+            None => {}
+        }
+        if let Some(id) = outcome.touch_latest {
+            touch_stmt.execute(params![now, id])?;
+        }
+        for skin in &outcome.inserts {
+            history_stmt.execute(params![name, region, skin, now, now])?;
+        }
+        changes.extend(outcome.changes.into_iter().map(|skin| SkinChange {
+            name: name.to_string(),
+            region: region.to_string(),
+            skin: ids.data(skin),
+        }));
+    }
+
+    info!(
+        "Skins: {} seen, {} refreshed, {} changed, {} new",
+        groups.len(),
+        refreshed,
+        changes.len(),
+        inserted
+    );
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::bench::{self, Rng};
+    use crate::migrations;
+    use crate::model::{Client, MapInfo, Server, ServerInfo};
+
+    // the tracker as it was before the bulk load, one query per client on the old schema that kept
+    // skins as json. the new one has to accept and record exactly the same skins.
+    mod reference {
+        use rusqlite::{params, OptionalExtension, Transaction};
+
+        use crate::model::ServerList;
+
+        pub const SCHEMA: &str = "
+            CREATE TABLE clients (name TEXT, region TEXT, current_skin TEXT, current_skin_time INTEGER, PRIMARY KEY (name, region));
+            CREATE TABLE skin_history (id INTEGER PRIMARY KEY, name TEXT, region TEXT, skin TEXT, first_seen INTEGER, last_seen INTEGER);
+        ";
+
+        fn client(tx: &Transaction, name: &str, region: &str) -> Option<(String, i64)> {
+            tx.query_row(
+                "SELECT current_skin, current_skin_time FROM clients WHERE name = ? AND region = ?",
+                params![name, region],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .unwrap()
+        }
+
+        fn set_client(tx: &Transaction, name: &str, region: &str, skin: &str, now: i64) {
+            tx.execute(
+                "INSERT OR REPLACE INTO clients (name, region, current_skin, current_skin_time) VALUES (?, ?, ?, ?)",
+                params![name, region, skin, now],
+            )
+            .unwrap();
+        }
+
+        fn record(tx: &Transaction, name: &str, region: &str, skin: &str, now: i64) {
+            let updated = tx
+                .execute(
+                    "UPDATE skin_history SET last_seen = ? WHERE id = (SELECT id FROM skin_history WHERE name = ? AND region = ? ORDER BY first_seen DESC, id DESC LIMIT 1) AND skin = ?",
+                    params![now, name, region, skin],
+                )
+                .unwrap();
+            if updated == 0 {
+                tx.execute(
+                    "INSERT INTO skin_history (name, region, skin, first_seen, last_seen) VALUES (?, ?, ?, ?, ?)",
+                    params![name, region, skin, now, now],
+                )
+                .unwrap();
+            }
+        }
+
+        pub fn update(
+            tx: &Transaction,
+            servers_data: &ServerList,
+            now: i64,
+            change_delay: i64,
+        ) -> Vec<(String, String, String)> {
+            let skinned: Vec<(&str, &str, String)> = servers_data
+                .servers
+                .iter()
+                .flat_map(|server| {
+                    server.info.clients.iter().filter_map(|client| {
+                        Some((
+                            client.name.as_str(),
+                            server.location.as_deref()?,
+                            client.skin.as_ref()?.data(),
+                        ))
+                    })
+                })
+                .collect();
+
+            for (name, region, skin) in &skinned {
+                if let Some((current, _)) = client(tx, name, region) {
+                    if current == *skin {
+                        tx.execute(
+                            "UPDATE clients SET current_skin_time = ? WHERE name = ? AND region = ?",
+                            params![now, name, region],
+                        )
+                        .unwrap();
+                        record(tx, name, region, skin, now);
+                    }
+                }
+            }
+
+            let mut changes = Vec::new();
+            for (name, region, skin) in &skinned {
+                match client(tx, name, region) {
+                    Some((current, time)) => {
+                        if current != *skin && time + change_delay < now {
+                            set_client(tx, name, region, skin, now);
+                            record(tx, name, region, skin, now);
+                            changes.push((name.to_string(), region.to_string(), skin.clone()));
+                        }
+                    }
+                    None => {
+                        set_client(tx, name, region, skin, now);
+                        record(tx, name, region, skin, now);
+                    }
+                }
+            }
+            changes
+        }
+    }
+
+    fn skin(name: &str, body: Option<i64>) -> Option<Skin> {
+        Some(Skin {
+            name: Some(name.to_string()),
+            color_body: body,
+            color_feet: body,
+        })
+    }
+
+    fn server(address: &str, region: &str, clients: Vec<(&str, Option<Skin>)>) -> Server {
+        Server {
+            addresses: vec![address.to_string()],
+            location: Some(region.to_string()),
+            info: ServerInfo {
+                map: MapInfo::default(),
+                clients: clients
+                    .into_iter()
+                    .map(|(name, skin)| Client {
+                        name: name.to_string(),
+                        clan: String::new(),
+                        country: -1,
+                        score: 0,
+                        is_player: true,
+                        skin,
+                        afk: false,
+                        team: 0,
+                    })
+                    .collect(),
+                ..ServerInfo::default()
+            },
+        }
+    }
+
+    /// Rows of `clients` and `skin_history` with the skin in its json form
+    type Tables = (
+        Vec<(String, String, String, i64)>,
+        Vec<(String, String, String, i64, i64)>,
+    );
+
+    /// Servers with names that show up more than once in a tick with different skins, whose skins
+    /// change back and forth inside and outside the delay window
+    fn shared_names(tick: usize) -> Vec<Server> {
+        let flip = if tick.is_multiple_of(2) {
+            "pinky"
+        } else {
+            "cammo"
+        };
+        let mut servers = vec![
+            server(
+                "tw-0.6+udp://10.255.0.1:8303",
+                "as:cn",
+                vec![
+                    ("twice", skin("default", None)),
+                    ("twice", skin("bluekitty", Some(1))),
+                    ("flipper", skin(flip, None)),
+                    (
+                        "bare",
+                        Some(Skin {
+                            name: None,
+                            color_body: None,
+                            color_feet: None,
+                        }),
+                    ),
+                    ("no skin", None),
+                ],
+            ),
+            server(
+                "tw-0.6+udp://10.255.0.2:8303",
+                "as:cn",
+                vec![
+                    ("twice", skin("bluekitty", Some(2))),
+                    ("flipper", skin("pinky", None)),
+                ],
+            ),
+        ];
+        // gone for a few ticks, then back in another skin
+        if !(4..8).contains(&tick) {
+            let late = if tick < 4 { "coala" } else { "mouse" };
+            servers.push(server(
+                "tw-0.6+udp://10.255.0.3:8303",
+                "eu:de",
+                vec![
+                    ("twice", skin(late, None)),
+                    ("comeback", skin(late, Some(3))),
+                ],
+            ));
+        }
+        servers
+    }
+
+    fn reference_state(conn: &Connection) -> Tables {
+        let clients = conn
+            .prepare("SELECT name, region, current_skin, current_skin_time FROM clients ORDER BY name, region")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        let history = conn
+            .prepare("SELECT name, region, skin, first_seen, last_seen FROM skin_history ORDER BY name, region, first_seen, id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        (clients, history)
+    }
+
+    /// Same as `reference_state`, skins turned back into their json form
+    fn state(conn: &Connection) -> Tables {
+        let data = |row: &rusqlite::Row, index: usize| -> rusqlite::Result<String> {
+            Ok(Skin {
+                name: row.get(index)?,
+                color_body: row.get(index + 1)?,
+                color_feet: row.get(index + 2)?,
+            }
+            .data())
+        };
+        let clients = conn
+            .prepare("SELECT c.name, c.region, s.name, s.body, s.feet, c.current_skin_time FROM clients c JOIN skins s ON s.id = c.current_skin_id ORDER BY c.name, c.region")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, data(row, 2)?, row.get(5)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        let history = conn
+            .prepare("SELECT h.name, h.region, s.name, s.body, s.feet, h.first_seen, h.last_seen FROM skin_history h JOIN skins s ON s.id = h.skin_id ORDER BY h.name, h.region, h.first_seen, h.id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, data(row, 2)?, row.get(5)?, row.get(6)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        (clients, history)
+    }
+
+    #[test]
+    fn accepts_the_same_skins_as_the_per_client_queries() {
+        for delay in [0, 5, 20] {
+            let mut old = Connection::open_in_memory().unwrap();
+            old.execute_batch(reference::SCHEMA).unwrap();
+            let mut new = Connection::open_in_memory().unwrap();
+            migrations::run(&mut new, false).unwrap();
+
+            let base = bench::fixture(400);
+            let mut list = base.clone();
+            let mut rng = Rng::new(0x0b5e_55ed);
+            let mut now = 29_000_000;
+            for tick in 0..30 {
+                if tick > 0 {
+                    bench::rotate_skins(&mut list, &mut rng);
+                    // now and then the tracker misses a few ticks
+                    now += if tick % 7 == 0 { 4 } else { 1 };
+                }
+                let mut servers_data = list.clone();
+                servers_data.servers.extend(shared_names(tick));
+
+                let tx = old.transaction().unwrap();
+                let mut expected = reference::update(&tx, &servers_data, now, delay);
+                tx.commit().unwrap();
+                let tx = new.transaction().unwrap();
+                let mut changes: Vec<(String, String, String)> =
+                    update(&tx, &servers_data, now, delay)
+                        .unwrap()
+                        .into_iter()
+                        .map(|change| (change.name, change.region, change.skin))
+                        .collect();
+                tx.commit().unwrap();
+
+                expected.sort();
+                changes.sort();
+                assert_eq!(
+                    changes, expected,
+                    "changes in tick {} with delay {}",
+                    tick, delay
+                );
+                assert_eq!(
+                    state(&new),
+                    reference_state(&old),
+                    "tables after tick {} with delay {}",
+                    tick,
+                    delay
+                );
+            }
+        }
+    }
+}
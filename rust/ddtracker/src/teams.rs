@@ -0,0 +1,208 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use log::info;
+use rusqlite::{params, Connection, Transaction};
+
+use crate::model::ServerList;
+
+// a team is two or more clients sharing a ddrace team number on one server and map. it stays the
+// same team while the map stays and some of its members are still in it, members that join later
+// are added with their own times. team 0 is no team and 64 is the super team, neither counts.
+// `end_time` is the last minute the team was seen, `online` is cleared once it's gone.
+
+const TEAM_SUPER: i64 = 64;
+
+pub fn close_all(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.execute("UPDATE teams SET online = 0 WHERE online = 1", [])
+}
+
+struct OpenTeam {
+    id: i64,
+    map: String,
+    members: HashSet<String>,
+}
+
+pub fn update(tx: &Transaction, servers_data: &ServerList, now: i64) -> rusqlite::Result<()> {
+    // every team has members, so joining them finds all open teams
+    let mut open: HashMap<(String, i64), OpenTeam> = HashMap::new();
+    {
+        let mut stmt = tx.prepare_cached(
+            "SELECT t.id, t.address, t.team, t.map, m.name FROM teams t JOIN team_members m ON m.team_id = t.id WHERE t.online = 1",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let id = row.get(0)?;
+            let map = row.get(3)?;
+            open.entry((row.get(1)?, row.get(2)?))
+                .or_insert_with(|| OpenTeam {
+                    id,
+                    map,
+                    members: HashSet::new(),
+                })
+                .members
+                .insert(row.get(4)?);
+        }
+    }
+
+    let mut extend_stmt = tx.prepare_cached("UPDATE teams SET end_time = ? WHERE id = ?")?;
+    let mut close_stmt = tx.prepare_cached("UPDATE teams SET online = 0 WHERE id = ?")?;
+    let mut insert_stmt = tx.prepare_cached(
+        "INSERT INTO teams (address, map, team, start_time, end_time, online) VALUES (?1, ?2, ?3, ?4, ?4, 1)",
+    )?;
+    let mut member_stmt = tx.prepare_cached(
+        "INSERT INTO team_members (team_id, name, first_seen, last_seen) VALUES (?1, ?2, ?3, ?3)
+        ON CONFLICT (team_id, name) DO UPDATE SET last_seen = excluded.last_seen",
+    )?;
+
+    let (mut formed, mut active, mut closed) = (0, 0, 0);
+    for server in &servers_data.servers {
+        let Some(address) = server.address() else {
+            continue;
+        };
+        let map = server.info.map.name.as_str();
+
+        let mut teams: BTreeMap<i64, HashSet<&str>> = BTreeMap::new();
+        for client in &server.info.clients {
+            if client.team > 0 && client.team < TEAM_SUPER {
+                teams
+                    .entry(client.team)
+                    .or_default()
+                    .insert(client.name.as_str());
+            }
+        }
+
+        for (number, members) in teams {
+            if members.len() < 2 {
+                continue;
+            }
+            let id = match open.remove(&(address.to_string(), number)) {
+                Some(team)
+                    if team.map == map
+                        && members.iter().any(|name| team.members.contains(*name)) =>
+                {
+                    extend_stmt.execute(params![now, team.id])?;
+                    team.id
+                }
+                previous => {
+                    if let Some(team) = previous {
+                        // another map or all new players, the old team is over
+                        close_stmt.execute(params![team.id])?;
+                        closed += 1;
+                    }
+                    insert_stmt.execute(params![address, map, number, now])?;
+                    formed += 1;
+                    tx.last_insert_rowid()
+                }
+            };
+            for name in members {
+                member_stmt.execute(params![id, name, now])?;
+            }
+            active += 1;
+        }
+    }
+
+    // teams left have split up, finished or their server is gone
+    for team in open.values() {
+        close_stmt.execute(params![team.id])?;
+        closed += 1;
+    }
+
+    info!(
+        "Teams: {} formed, {} closed, {} active",
+        formed, closed, active
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::{migrations, model};
+
+    fn tick(conn: &mut Connection, map: &str, clients: &[(&str, i64)], now: i64) {
+        let clients: Vec<String> = clients
+            .iter()
+            .map(|(name, team)| format!(r#"{{"name":"{}","team":{}}}"#, name, team))
+            .collect();
+        let (servers, _) = model::parse(&format!(
+            r#"{{"servers":[{{"addresses":["tw-0.6+udp://1.1.1.1:8303"],"location":"eu:de","info":{{"map":{{"name":"{}"}},"clients":[{}]}}}}]}}"#,
+            map,
+            clients.join(",")
+        ))
+        .unwrap();
+        let tx = conn.transaction().unwrap();
+        update(&tx, &servers, now).unwrap();
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn teams_last_while_members_overlap() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn, false).unwrap();
+
+        // the super team and a team of one aren't teams
+        tick(
+            &mut conn,
+            "Kobra",
+            &[
+                ("A", 3),
+                ("B", 3),
+                ("C", TEAM_SUPER),
+                ("D", TEAM_SUPER),
+                ("E", 5),
+            ],
+            100,
+        );
+        tick(&mut conn, "Kobra", &[("B", 3), ("C", 3)], 101);
+        tick(&mut conn, "Kobra", &[("X", 3), ("Y", 3)], 102);
+        tick(&mut conn, "Linear", &[("X", 3), ("Y", 3)], 103);
+
+        let teams: Vec<(i64, String, i64, i64, bool)> = conn
+            .prepare("SELECT id, map, start_time, end_time, online FROM teams ORDER BY id")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let maps: Vec<(&str, i64, i64, bool)> = teams
+            .iter()
+            .map(|(_, map, start, end, online)| (map.as_str(), *start, *end, *online))
+            .collect();
+        assert_eq!(
+            maps,
+            vec![
+                ("Kobra", 100, 101, false),
+                ("Kobra", 102, 102, false),
+                ("Linear", 103, 103, true),
+            ]
+        );
+
+        let members: Vec<(String, i64, i64)> = conn
+            .prepare("SELECT name, first_seen, last_seen FROM team_members WHERE team_id = ? ORDER BY name")
+            .unwrap()
+            .query_map([teams[0].0], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            members,
+            vec![
+                ("A".to_string(), 100, 100),
+                ("B".to_string(), 100, 101),
+                ("C".to_string(), 101, 101),
+            ]
+        );
+
+        assert_eq!(close_all(&conn).unwrap(), 1);
+    }
+}
@@ -0,0 +1,788 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, warn};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::{Duration, Instant};
+
+use crate::model::{Client, MapInfo, ServerInfo};
+
+// asks game servers for their info directly, the same way the in-game browser does.
+//
+// 0.6: connless packets start with six 0xff bytes. a `gie3` request sent with the ddnet "xe" header
+// instead (two bytes "xe" and the upper token bytes) is answered with the extended info, `iext`
+// followed by as many `iex+` packets as the player list needs. servers that don't know the extended
+// info drop it, so a plain `gie3` goes out as well and its `inf3` answer (16 clients at most) is
+// only used when the extended one doesn't arrive. every field is a null terminated string.
+//
+// 0.7: connless packets carry a token of the server and one of ours, the server's token is asked for
+// first with a padded control message. the info is made of null terminated strings and packed
+// ints. the info request goes out once the token arrived.
+
+const GETINFO: &[u8] = b"\xff\xff\xff\xffgie3";
+const INFO: &[u8] = b"\xff\xff\xff\xffinf3";
+const INFO_EXTENDED: &[u8] = b"\xff\xff\xff\xffiext";
+const INFO_EXTENDED_MORE: &[u8] = b"\xff\xff\xff\xffiex+";
+
+const LEGACY_HEADER_SIZE: usize = 6;
+
+const SEVEN_FLAG_CONTROL: u8 = 1 << 2;
+const SEVEN_FLAG_CONNLESS: u8 = 8 << 2;
+const SEVEN_PACKET_VERSION: u8 = 1;
+const SEVEN_HEADER_SIZE: usize = 7;
+const SEVEN_CONNLESS_HEADER_SIZE: usize = 9;
+const SEVEN_CTRLMSG_TOKEN: u8 = 5;
+const SEVEN_TOKEN_NONE: u32 = 0xffff_ffff;
+/// Servers ignore token requests that are shorter, so they can't be used for reflection
+const SEVEN_TOKEN_REQUEST_SIZE: usize = 512;
+
+const MAX_PACKET_SIZE: usize = 1400;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Legacy,
+    Seven,
+}
+
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub address: SocketAddr,
+    pub protocol: Protocol,
+}
+
+impl Target {
+    /// Parses `tw-0.6+udp://ip:port`, `tw-0.7+udp://ip:port` or a bare `ip:port`, which is 0.6
+    pub fn parse(address: &str) -> Option<Self> {
+        let (protocol, rest) = if let Some(rest) = address.strip_prefix("tw-0.6+udp://") {
+            (Protocol::Legacy, rest)
+        } else if let Some(rest) = address.strip_prefix("tw-0.7+udp://") {
+            (Protocol::Seven, rest)
+        } else if address.contains("://") {
+            return None;
+        } else {
+            (Protocol::Legacy, address)
+        };
+        Some(Target {
+            address: rest.parse().ok()?,
+            protocol,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Extended {
+    info: Option<ServerInfo>,
+    num_clients: usize,
+    /// Clients by packet number, `iext` being packet 0
+    packets: BTreeMap<i64, Vec<Client>>,
+}
+
+impl Extended {
+    fn received(&self) -> usize {
+        self.packets.values().map(Vec::len).sum()
+    }
+
+    fn complete(&self) -> bool {
+        self.info.is_some() && self.received() >= self.num_clients
+    }
+}
+
+struct State {
+    protocol: Protocol,
+    token: u32,
+    extended: Extended,
+    vanilla: Option<(ServerInfo, usize)>,
+    seven: Option<ServerInfo>,
+}
+
+impl State {
+    fn new(protocol: Protocol) -> Self {
+        Self {
+            protocol,
+            // 0.7 doesn't allow the all-ones token
+            token: (uuid::Uuid::new_v4().as_u128() as u32).min(SEVEN_TOKEN_NONE - 1),
+            extended: Extended::default(),
+            vanilla: None,
+            seven: None,
+        }
+    }
+
+    fn done(&self) -> bool {
+        match self.protocol {
+            Protocol::Legacy => self.extended.complete(),
+            Protocol::Seven => self.seven.is_some(),
+        }
+    }
+
+    /// The most complete info received
+    fn finish(self) -> Option<ServerInfo> {
+        match self.protocol {
+            Protocol::Seven => self.seven,
+            Protocol::Legacy => {
+                let vanilla_complete = self
+                    .vanilla
+                    .as_ref()
+                    .is_some_and(|(info, num_clients)| info.clients.len() >= *num_clients);
+                let extended_received = self.extended.received();
+                match (self.extended, self.vanilla) {
+                    (extended, _) if extended.complete() => extended_info(extended),
+                    (_, Some((vanilla, _))) if vanilla_complete => Some(vanilla),
+                    (extended, Some((vanilla, _))) => {
+                        if extended.info.is_some() && extended_received > vanilla.clients.len() {
+                            extended_info(extended)
+                        } else {
+                            Some(vanilla)
+                        }
+                    }
+                    (extended, None) => extended_info(extended),
+                }
+            }
+        }
+    }
+}
+
+fn extended_info(extended: Extended) -> Option<ServerInfo> {
+    let mut info = extended.info?;
+    info.clients = extended.packets.into_values().flatten().collect();
+    Some(info)
+}
+
+/// One socket per address family. A family the host can't bind, like ipv6 on an ipv4-only host, is
+/// left out and its targets go unanswered.
+pub struct Sockets {
+    v4: Option<Arc<UdpSocket>>,
+    v6: Option<Arc<UdpSocket>>,
+}
+
+impl Sockets {
+    /// Binds a socket for every address family used by the addresses
+    pub async fn bind<'a>(addresses: impl IntoIterator<Item = &'a SocketAddr>) -> Self {
+        let (mut needs_v4, mut needs_v6) = (false, false);
+        for address in addresses {
+            needs_v4 |= address.is_ipv4();
+            needs_v6 |= address.is_ipv6();
+        }
+        Self {
+            v4: if needs_v4 {
+                bind("0.0.0.0:0").await
+            } else {
+                None
+            },
+            v6: if needs_v6 { bind("[::]:0").await } else { None },
+        }
+    }
+
+    /// Whether the address's family has a socket
+    pub fn supports(&self, address: &SocketAddr) -> bool {
+        self.get(address).is_some()
+    }
+
+    fn get(&self, address: &SocketAddr) -> Option<&Arc<UdpSocket>> {
+        if address.is_ipv4() {
+            self.v4.as_ref()
+        } else {
+            self.v6.as_ref()
+        }
+    }
+}
+
+async fn bind(address: &str) -> Option<Arc<UdpSocket>> {
+    match UdpSocket::bind(address).await {
+        Ok(socket) => Some(Arc::new(socket)),
+        Err(e) => {
+            warn!(
+                "Failed to bind udp socket on {}, its servers go unanswered: {}",
+                address, e
+            );
+            None
+        }
+    }
+}
+
+/// Polls every target at once and waits for the answers until `timeout`. The result has one entry
+/// per target, `None` for those that didn't answer or whose address family has no socket.
+pub async fn poll(
+    sockets: &Sockets,
+    targets: &[Target],
+    timeout: Duration,
+) -> Vec<Option<ServerInfo>> {
+    let socket_for = |address: &SocketAddr| sockets.get(address);
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut receivers = JoinSet::new();
+    for socket in [&sockets.v4, &sockets.v6].into_iter().flatten() {
+        let socket = socket.clone();
+        let sender = sender.clone();
+        receivers.spawn(async move {
+            let mut buf = [0u8; MAX_PACKET_SIZE * 2];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                if sender.send((buf[..len].to_vec(), from)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(sender);
+
+    let mut states: Vec<State> = targets
+        .iter()
+        .map(|target| State::new(target.protocol))
+        .collect();
+    // one server can be listed twice, only its first entry is asked
+    let mut by_address: HashMap<SocketAddr, usize> = HashMap::new();
+    for (index, target) in targets.iter().enumerate() {
+        let Some(socket) = socket_for(&target.address) else {
+            continue;
+        };
+        if by_address.contains_key(&target.address) {
+            continue;
+        }
+        by_address.insert(target.address, index);
+        for packet in requests(&states[index]) {
+            if let Err(e) = socket.send_to(&packet, target.address).await {
+                debug!("Failed to send info request to {}: {}", target.address, e);
+            }
+        }
+    }
+
+    let mut remaining = by_address.len();
+    let deadline = Instant::now() + timeout;
+    while remaining > 0 {
+        let Ok(Some((packet, from))) = tokio::time::timeout_at(deadline, receiver.recv()).await
+        else {
+            break;
+        };
+        let Some(&index) = by_address.get(&from) else {
+            continue;
+        };
+        let state = &mut states[index];
+        if state.done() {
+            continue;
+        }
+        let reply = match state.protocol {
+            Protocol::Legacy => {
+                handle_legacy(state, &packet);
+                None
+            }
+            Protocol::Seven => handle_seven(state, &packet),
+        };
+        if let (Some(reply), Some(socket)) = (reply, socket_for(&from)) {
+            if let Err(e) = socket.send_to(&reply, from).await {
+                debug!("Failed to send info request to {}: {}", from, e);
+            }
+        }
+        if state.done() {
+            remaining -= 1;
+        }
+    }
+
+    states.into_iter().map(State::finish).collect()
+}
+
+/// The first packets sent to a server
+fn requests(state: &State) -> Vec<Vec<u8>> {
+    let token = state.token;
+    match state.protocol {
+        Protocol::Legacy => {
+            let mut extended = vec![b'x', b'e', (token >> 16) as u8, (token >> 8) as u8, 0, 0];
+            extended.extend_from_slice(GETINFO);
+            extended.push(token as u8);
+
+            let mut vanilla = vec![0xff; LEGACY_HEADER_SIZE];
+            vanilla.extend_from_slice(GETINFO);
+            vanilla.push(token as u8);
+
+            vec![extended, vanilla]
+        }
+        Protocol::Seven => {
+            let mut packet = vec![SEVEN_FLAG_CONTROL, 0, 0];
+            packet.extend_from_slice(&SEVEN_TOKEN_NONE.to_be_bytes());
+            packet.push(SEVEN_CTRLMSG_TOKEN);
+            packet.extend_from_slice(&token.to_be_bytes());
+            packet.resize(SEVEN_HEADER_SIZE + 1 + SEVEN_TOKEN_REQUEST_SIZE, 0);
+            vec![packet]
+        }
+    }
+}
+
+fn handle_legacy(state: &mut State, packet: &[u8]) -> Option<()> {
+    let header = packet.get(..LEGACY_HEADER_SIZE)?;
+    if !header.starts_with(b"xe") && header.iter().any(|&byte| byte != 0xff) {
+        return None;
+    }
+    let data = &packet[LEGACY_HEADER_SIZE..];
+    let magic = data.get(..GETINFO.len())?;
+    let mut unpacker = Unpacker::new(&data[GETINFO.len()..]);
+    let token = unpacker.int_string()?;
+
+    if magic == INFO {
+        if token != i64::from(state.token & 0xff) {
+            return None;
+        }
+        let (mut info, num_clients) = legacy_info(&mut unpacker, false)?;
+        info.clients = legacy_clients(&mut unpacker, false);
+        state.vanilla = Some((info, num_clients));
+    } else if magic == INFO_EXTENDED {
+        if token != i64::from(state.token & 0xff_ffff) {
+            return None;
+        }
+        let (info, num_clients) = legacy_info(&mut unpacker, true)?;
+        unpacker.string()?; // reserved
+        state.extended.info = Some(info);
+        state.extended.num_clients = num_clients;
+        state
+            .extended
+            .packets
+            .insert(0, legacy_clients(&mut unpacker, true));
+    } else if magic == INFO_EXTENDED_MORE {
+        if token != i64::from(state.token & 0xff_ffff) {
+            return None;
+        }
+        let number = unpacker.int_string()?;
+        unpacker.string()?; // reserved
+        state
+            .extended
+            .packets
+            .insert(number, legacy_clients(&mut unpacker, true));
+    }
+    Some(())
+}
+
+/// Server fields of `inf3` and `iext`, and the number of clients the list should have
+fn legacy_info(unpacker: &mut Unpacker, extended: bool) -> Option<(ServerInfo, usize)> {
+    let version = unpacker.string()?;
+    let name = unpacker.string()?;
+    let map = unpacker.string()?;
+    let mut map_size = None;
+    if extended {
+        unpacker.int_string()?; // crc
+        map_size = Some(unpacker.int_string()?);
+    }
+    let game_type = unpacker.string()?;
+    let flags = unpacker.int_string()?;
+    let _num_players = unpacker.int_string()?;
+    let max_players = unpacker.int_string()?;
+    let num_clients = unpacker.int_string()?;
+    let max_clients = unpacker.int_string()?;
+    Some((
+        ServerInfo {
+            max_clients,
+            max_players,
+            passworded: flags & 1 != 0,
+            game_type,
+            name,
+            map: MapInfo {
+                name: map,
+                sha256: None,
+                size: map_size,
+            },
+            version,
+            clients: Vec::new(),
+        },
+        num_clients.max(0) as usize,
+    ))
+}
+
+fn legacy_clients(unpacker: &mut Unpacker, extended: bool) -> Vec<Client> {
+    let mut clients = Vec::new();
+    loop {
+        let Some(client) = (|| {
+            let name = unpacker.string()?;
+            let clan = unpacker.string()?;
+            let country = unpacker.int_string()?;
+            let score = unpacker.int_string()?;
+            let is_player = unpacker.int_string()? != 0;
+            if extended {
+                unpacker.string()?; // reserved
+            }
+            Some(client(name, clan, country, score, is_player))
+        })() else {
+            return clients;
+        };
+        clients.push(client);
+    }
+}
+
+/// Answers the token with the info request, or reads the info
+fn handle_seven(state: &mut State, packet: &[u8]) -> Option<Vec<u8>> {
+    let flags = *packet.first()?;
+    if flags & SEVEN_FLAG_CONNLESS != 0 {
+        let header = packet.get(..SEVEN_CONNLESS_HEADER_SIZE)?;
+        if u32::from_be_bytes(header[1..5].try_into().ok()?) != state.token {
+            return None;
+        }
+        let data = &packet[SEVEN_CONNLESS_HEADER_SIZE..];
+        if data.get(..INFO.len())? != INFO {
+            return None;
+        }
+        let mut unpacker = Unpacker::new(&data[INFO.len()..]);
+        if unpacker.packed_int()? != i64::from(state.token & 0xff_ffff) {
+            return None;
+        }
+        state.seven = Some(seven_info(&mut unpacker)?);
+        return None;
+    }
+
+    if flags & SEVEN_FLAG_CONTROL == 0 {
+        return None;
+    }
+    let header = packet.get(..SEVEN_HEADER_SIZE)?;
+    if u32::from_be_bytes(header[3..7].try_into().ok()?) != state.token
+        || *packet.get(SEVEN_HEADER_SIZE)? != SEVEN_CTRLMSG_TOKEN
+    {
+        return None;
+    }
+    let server_token = packet.get(SEVEN_HEADER_SIZE + 1..SEVEN_HEADER_SIZE + 5)?;
+
+    let mut request = vec![SEVEN_FLAG_CONNLESS | SEVEN_PACKET_VERSION];
+    request.extend_from_slice(server_token);
+    request.extend_from_slice(&state.token.to_be_bytes());
+    request.extend_from_slice(GETINFO);
+    pack_int(&mut request, state.token & 0xff_ffff);
+    Some(request)
+}
+
+fn seven_info(unpacker: &mut Unpacker) -> Option<ServerInfo> {
+    let version = unpacker.string()?;
+    let name = unpacker.string()?;
+    let _hostname = unpacker.string()?;
+    let map = unpacker.string()?;
+    let game_type = unpacker.string()?;
+    let flags = unpacker.packed_int()?;
+    let _skill_level = unpacker.packed_int()?;
+    let _num_players = unpacker.packed_int()?;
+    let max_players = unpacker.packed_int()?;
+    let _num_clients = unpacker.packed_int()?;
+    let max_clients = unpacker.packed_int()?;
+
+    let mut clients = Vec::new();
+    while let Some(name) = unpacker.string() {
+        let clan = unpacker.string()?;
+        let country = unpacker.packed_int()?;
+        let score = unpacker.packed_int()?;
+        // 1 is spectator, 2 is bot
+        let player_flags = unpacker.packed_int()?;
+        clients.push(client(name, clan, country, score, player_flags & 1 == 0));
+    }
+
+    Some(ServerInfo {
+        max_clients,
+        max_players,
+        passworded: flags & 1 != 0,
+        game_type,
+        name,
+        map: MapInfo {
+            name: map,
+            sha256: None,
+            size: None,
+        },
+        version,
+        clients,
+    })
+}
+
+fn client(name: String, clan: String, country: i64, score: i64, is_player: bool) -> Client {
+    Client {
+        name,
+        clan,
+        country,
+        score,
+        is_player,
+        skin: None,
+        afk: false,
+        team: 0,
+    }
+}
+
+fn pack_int(out: &mut Vec<u8>, value: u32) {
+    let mut byte = (value & 0x3f) as u8;
+    let mut value = value >> 6;
+    while value != 0 {
+        out.push(byte | 0x80);
+        byte = (value & 0x7f) as u8;
+        value >>= 7;
+    }
+    out.push(byte);
+}
+
+struct Unpacker<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Unpacker<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let rest = self.data.get(self.pos..)?;
+        let end = rest.iter().position(|&byte| byte == 0)?;
+        self.pos += end + 1;
+        Some(String::from_utf8_lossy(&rest[..end]).into_owned())
+    }
+
+    /// 0.6 sends numbers as strings
+    fn int_string(&mut self) -> Option<i64> {
+        self.string()?.trim().parse().ok()
+    }
+
+    /// 0.7 variable length int, six bits and the sign in the first byte, seven in the others
+    fn packed_int(&mut self) -> Option<i64> {
+        let mut byte = *self.data.get(self.pos)?;
+        self.pos += 1;
+        let negative = byte & 0x40 != 0;
+        let mut value = i64::from(byte & 0x3f);
+        let mut shift = 6;
+        while byte & 0x80 != 0 && shift < 34 {
+            byte = *self.data.get(self.pos)?;
+            self.pos += 1;
+            value |= i64::from(byte & 0x7f) << shift;
+            shift += 7;
+        }
+        Some(if negative { !value } else { value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers every packet it receives with whatever `answer` makes of it
+    async fn responder(answer: impl Fn(&[u8]) -> Vec<Vec<u8>> + Send + 'static) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; MAX_PACKET_SIZE * 2];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                for packet in answer(&buf[..len]) {
+                    socket.send_to(&packet, from).await.unwrap();
+                }
+            }
+        });
+        address
+    }
+
+    async fn poll_one(target: &str, timeout: Duration) -> Option<ServerInfo> {
+        let target = Target::parse(target).unwrap();
+        let sockets = Sockets::bind([&target.address]).await;
+        poll(&sockets, &[target], timeout).await.pop().unwrap()
+    }
+
+    fn strings(out: &mut Vec<u8>, values: &[&str]) {
+        for value in values {
+            out.extend_from_slice(value.as_bytes());
+            out.push(0);
+        }
+    }
+
+    fn legacy_packet(magic: &[u8], token: u32, fields: &[&str]) -> Vec<u8> {
+        let mut packet = vec![0xff; LEGACY_HEADER_SIZE];
+        packet.extend_from_slice(magic);
+        strings(&mut packet, &[&token.to_string()]);
+        strings(&mut packet, fields);
+        packet
+    }
+
+    fn extended_client(name: &str) -> [&str; 6] {
+        [name, "clan", "156", "10", "1", ""]
+    }
+
+    fn pack_signed(out: &mut Vec<u8>, value: i64) {
+        if value < 0 {
+            let mut packed = Vec::new();
+            pack_int(&mut packed, !value as u32);
+            packed[0] |= 0x40;
+            out.extend(packed);
+        } else {
+            pack_int(out, value as u32);
+        }
+    }
+
+    #[test]
+    fn packed_ints_round_trip() {
+        for value in [
+            0,
+            1,
+            63,
+            64,
+            127,
+            128,
+            8191,
+            8192,
+            1 << 20,
+            -1,
+            -64,
+            -65,
+            -100_000,
+        ] {
+            let mut packed = Vec::new();
+            pack_signed(&mut packed, value);
+            assert_eq!(
+                Unpacker::new(&packed).packed_int(),
+                Some(value),
+                "{}",
+                value
+            );
+        }
+        let mut packed = Vec::new();
+        pack_int(&mut packed, 0xff_ffff);
+        assert_eq!(Unpacker::new(&packed).packed_int(), Some(0xff_ffff));
+    }
+
+    #[tokio::test]
+    async fn extended_info_split_over_packets() {
+        let address = responder(|request| {
+            if !request.starts_with(b"xe") {
+                return Vec::new();
+            }
+            let token = u32::from(request[2]) << 16
+                | u32::from(request[3]) << 8
+                | u32::from(*request.last().unwrap());
+            let mut info = legacy_packet(
+                INFO_EXTENDED,
+                token,
+                &[
+                    "0.6.4",
+                    "Test",
+                    "Tutorial",
+                    "0",
+                    "1234",
+                    "DDraceNetwork",
+                    "0",
+                    "5",
+                    "64",
+                    "5",
+                    "64",
+                    "",
+                ],
+            );
+            strings(&mut info, &extended_client("a"));
+            strings(&mut info, &extended_client("b"));
+            let mut second = legacy_packet(INFO_EXTENDED_MORE, token, &["2", ""]);
+            strings(&mut second, &extended_client("e"));
+            let mut first = legacy_packet(INFO_EXTENDED_MORE, token, &["1", ""]);
+            strings(&mut first, &extended_client("c"));
+            strings(&mut first, &extended_client("d"));
+            // out of order, the packet number decides
+            vec![info, second, first]
+        })
+        .await;
+
+        let info = poll_one(&format!("tw-0.6+udp://{}", address), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(info.name, "Test");
+        assert_eq!(info.map.name, "Tutorial");
+        assert_eq!(info.map.size, Some(1234));
+        assert_eq!(info.max_clients, 64);
+        let names: Vec<&str> = info.clients.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "c", "d", "e"]);
+        assert_eq!(info.clients[0].clan, "clan");
+        assert_eq!(info.clients[0].country, 156);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_vanilla_info() {
+        let address = responder(|request| {
+            if request.starts_with(b"xe") {
+                return Vec::new();
+            }
+            let token = u32::from(*request.last().unwrap());
+            let mut info = legacy_packet(
+                INFO,
+                token,
+                &["0.6.4", "Vanilla", "dm1", "DM", "1", "2", "16", "2", "16"],
+            );
+            strings(&mut info, &["x", "", "-1", "3", "1"]);
+            strings(&mut info, &["y", "", "-1", "0", "0"]);
+            vec![info]
+        })
+        .await;
+
+        let info = poll_one(&format!("{}", address), Duration::from_millis(300))
+            .await
+            .unwrap();
+        assert_eq!(info.name, "Vanilla");
+        assert!(info.passworded);
+        assert_eq!(info.map.size, None);
+        let names: Vec<&str> = info.clients.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["x", "y"]);
+        assert!(info.clients[0].is_player);
+        assert!(!info.clients[1].is_player);
+    }
+
+    #[tokio::test]
+    async fn seven_token_handshake() {
+        const SERVER_TOKEN: u32 = 0x1234_5678;
+        let address = responder(|request| {
+            let flags = request[0];
+            if flags & SEVEN_FLAG_CONTROL != 0 {
+                assert_eq!(
+                    request.len(),
+                    SEVEN_HEADER_SIZE + 1 + SEVEN_TOKEN_REQUEST_SIZE
+                );
+                assert_eq!(request[SEVEN_HEADER_SIZE], SEVEN_CTRLMSG_TOKEN);
+                let mut reply = vec![SEVEN_FLAG_CONTROL, 0, 0];
+                reply.extend_from_slice(&request[SEVEN_HEADER_SIZE + 1..SEVEN_HEADER_SIZE + 5]);
+                reply.push(SEVEN_CTRLMSG_TOKEN);
+                reply.extend_from_slice(&SERVER_TOKEN.to_be_bytes());
+                return vec![reply];
+            }
+            assert_ne!(flags & SEVEN_FLAG_CONNLESS, 0);
+            assert_eq!(request[1..5], SERVER_TOKEN.to_be_bytes());
+            let client_token = &request[5..9];
+            assert_eq!(
+                &request[SEVEN_CONNLESS_HEADER_SIZE..][..GETINFO.len()],
+                GETINFO
+            );
+            let token = Unpacker::new(&request[SEVEN_CONNLESS_HEADER_SIZE + GETINFO.len()..])
+                .packed_int()
+                .unwrap();
+
+            let mut reply = vec![SEVEN_FLAG_CONNLESS | SEVEN_PACKET_VERSION];
+            reply.extend_from_slice(client_token);
+            reply.extend_from_slice(&SERVER_TOKEN.to_be_bytes());
+            reply.extend_from_slice(INFO);
+            pack_signed(&mut reply, token);
+            strings(&mut reply, &["0.7.5", "Seven", "host", "ctf5", "CTF"]);
+            for value in [0, 0, 2, 16, 2, 16] {
+                pack_signed(&mut reply, value);
+            }
+            strings(&mut reply, &["p", "c"]);
+            for value in [276, -9999, 0] {
+                pack_signed(&mut reply, value);
+            }
+            strings(&mut reply, &["s", ""]);
+            for value in [-1, 100_000, 1] {
+                pack_signed(&mut reply, value);
+            }
+            vec![reply]
+        })
+        .await;
+
+        let info = poll_one(&format!("tw-0.7+udp://{}", address), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(info.name, "Seven");
+        assert_eq!(info.map.name, "ctf5");
+        assert_eq!(info.max_clients, 16);
+        assert_eq!(info.clients.len(), 2);
+        assert_eq!(info.clients[0].score, -9999);
+        assert!(info.clients[0].is_player);
+        assert_eq!(info.clients[1].country, -1);
+        assert_eq!(info.clients[1].score, 100_000);
+        assert!(!info.clients[1].is_player);
+    }
+
+    #[tokio::test]
+    async fn silent_server_is_unanswered() {
+        let address = responder(|_| Vec::new()).await;
+        assert!(poll_one(&address.to_string(), Duration::from_millis(100))
+            .await
+            .is_none());
+    }
+}
@@ -0,0 +1,511 @@
+use std::collections::{HashMap, HashSet};
+
+use log::{info, warn};
+use reqwest::Client;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use tokio::time::Duration;
+
+use crate::config::Config;
+use crate::model::ServerList;
+
+// watchers are rows of the watchlist table, a player name or a clan tag with a webhook. every tick
+// the watched players are looked up in the server list, a change of presence (online, other server,
+// offline) has to hold for `watch_debounce` ticks before it's announced, so a quick reconnect
+// doesn't notify anyone. after a notification the watcher stays quiet about that player for its
+// cooldown, a change in the meantime is held back and announced once the cooldown is over if the
+// player didn't go back to what was announced last. the first tick a watcher is seen only sets the
+// baseline.
+
+const COMMAND_USAGE: &str =
+    "Usage: ddtracker watch add <player|clan> <target> <webhook-url> [cooldown-minutes]
+       ddtracker watch list
+       ddtracker watch remove <id>";
+
+struct Watch {
+    id: i64,
+    kind: String,
+    target: String,
+    webhook_url: String,
+    /// Minutes
+    cooldown: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Location {
+    address: String,
+    name: String,
+    map: String,
+    region: String,
+}
+
+#[derive(Default)]
+struct Presence {
+    /// Last announced state, `None` is offline
+    confirmed: Option<Location>,
+    /// A different state and for how many ticks in a row it has been seen
+    pending: Option<(Option<Location>, u32)>,
+    last_notified: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct WatchInfo<'a> {
+    id: i64,
+    kind: &'a str,
+    target: &'a str,
+}
+
+#[derive(Serialize)]
+struct Notification<'a> {
+    event: &'static str,
+    /// Unix timestamp in milliseconds
+    time: i64,
+    watch: WatchInfo<'a>,
+    name: &'a str,
+    server: Option<&'a Location>,
+    previous: Option<&'a Location>,
+}
+
+pub struct Watchers {
+    client: Client,
+    known: HashSet<i64>,
+    presence: HashMap<(i64, String), Presence>,
+}
+
+impl Watchers {
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            known: HashSet::new(),
+            presence: HashMap::new(),
+        }
+    }
+
+    /// Checks every watcher against the tick's servers and sends the due notifications
+    pub fn update(
+        &mut self,
+        conn: &Connection,
+        servers_data: &ServerList,
+        now: i64,
+        config: &Config,
+    ) -> rusqlite::Result<()> {
+        let watches = load_watches(conn)?;
+
+        // name -> (where, clan), the first server a name shows up on wins
+        let mut online: HashMap<&str, (Location, &str)> = HashMap::new();
+        for server in &servers_data.servers {
+            let Some(address) = server.address() else {
+                continue;
+            };
+            for client in &server.info.clients {
+                online.entry(client.name.as_str()).or_insert_with(|| {
+                    (
+                        Location {
+                            address: address.to_string(),
+                            name: server.info.name.clone(),
+                            map: server.info.map.name.clone(),
+                            region: server.location.clone().unwrap_or_default(),
+                        },
+                        client.clan.as_str(),
+                    )
+                });
+            }
+        }
+
+        let ids: HashSet<i64> = watches.iter().map(|watch| watch.id).collect();
+        self.known.retain(|id| ids.contains(id));
+        self.presence.retain(|(id, _), _| ids.contains(id));
+
+        for watch in &watches {
+            let mut names: HashSet<String> = HashSet::new();
+            if watch.kind == "clan" {
+                names.extend(
+                    online
+                        .iter()
+                        .filter(|(_, (_, clan))| *clan == watch.target)
+                        .map(|(name, _)| name.to_string()),
+                );
+                names.extend(
+                    self.presence
+                        .keys()
+                        .filter(|(id, _)| *id == watch.id)
+                        .map(|(_, name)| name.clone()),
+                );
+            } else {
+                names.insert(watch.target.clone());
+            }
+
+            let baseline = self.known.insert(watch.id);
+            for name in names {
+                let observed = online.get(name.as_str()).map(|(location, _)| location);
+                let key = (watch.id, name);
+                let presence = self.presence.entry(key.clone()).or_default();
+
+                if baseline {
+                    presence.confirmed = observed.cloned();
+                } else if let Some(event) = presence.observe(observed, config.watch_debounce) {
+                    if presence
+                        .last_notified
+                        .is_some_and(|last| last + watch.cooldown > now)
+                    {
+                        // the announced state stays, so the change comes up again after the cooldown
+                        info!(
+                            "Watch {}: {} is {}, still cooling down",
+                            watch.id, key.1, event
+                        );
+                    } else {
+                        let previous =
+                            std::mem::replace(&mut presence.confirmed, observed.cloned());
+                        presence.last_notified = Some(now);
+                        let payload = Notification {
+                            event,
+                            time: now * 60000,
+                            watch: WatchInfo {
+                                id: watch.id,
+                                kind: &watch.kind,
+                                target: &watch.target,
+                            },
+                            name: &key.1,
+                            server: observed,
+                            previous: previous.as_ref(),
+                        };
+                        notify(&self.client, watch, &payload, config);
+                    }
+                }
+
+                // offline and settled, nothing left to remember but the cooldown
+                if presence.confirmed.is_none()
+                    && presence.pending.is_none()
+                    && presence
+                        .last_notified
+                        .is_none_or(|last| last + watch.cooldown <= now)
+                {
+                    self.presence.remove(&key);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn notify(client: &Client, watch: &Watch, payload: &Notification, config: &Config) {
+    info!(
+        "Watch {}: {} is {}, notifying {}",
+        watch.id, payload.name, payload.event, watch.webhook_url
+    );
+    if config.dry_run {
+        return;
+    }
+    let body = match serde_json::to_string(payload) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to serialize notification: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(deliver(
+        client.clone(),
+        watch.webhook_url.clone(),
+        body,
+        config.retries,
+        config.retry_backoff,
+    ));
+}
+
+impl Presence {
+    /// Feeds the state seen this tick, returns the event once a change has held long enough
+    fn observe(&mut self, observed: Option<&Location>, debounce: u32) -> Option<&'static str> {
+        let address = |location: Option<&Location>| location.map(|l| l.address.clone());
+        if address(observed) == address(self.confirmed.as_ref()) {
+            self.pending = None;
+            return None;
+        }
+
+        let ticks = match &self.pending {
+            Some((pending, ticks)) if address(pending.as_ref()) == address(observed) => ticks + 1,
+            _ => 1,
+        };
+        if ticks < debounce {
+            self.pending = Some((observed.cloned(), ticks));
+            return None;
+        }
+
+        self.pending = None;
+        Some(match (&self.confirmed, observed) {
+            (None, _) => "online",
+            (_, None) => "offline",
+            _ => "server_changed",
+        })
+    }
+}
+
+async fn deliver(client: Client, url: String, body: String, retries: u32, backoff: u64) {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let result = client
+            .post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => {
+                info!("Delivered notification to {}", url);
+                return;
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to deliver notification to {}, attempt {}: {}",
+                    url, attempt, e
+                );
+                if attempt > retries {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(backoff * 2u64.pow(attempt - 1))).await;
+            }
+        }
+    }
+}
+
+fn load_watches(conn: &Connection) -> rusqlite::Result<Vec<Watch>> {
+    let mut stmt =
+        conn.prepare("SELECT id, kind, target, webhook_url, cooldown FROM watchlist ORDER BY id")?;
+    let watches = stmt
+        .query_map([], |row| {
+            Ok(Watch {
+                id: row.get(0)?,
+                kind: row.get(1)?,
+                target: row.get(2)?,
+                webhook_url: row.get(3)?,
+                cooldown: row.get(4)?,
+            })
+        })?
+        .collect();
+    watches
+}
+
+/// `ddtracker watch ...`, manages the watchlist
+pub fn command(
+    conn: &Connection,
+    args: &[String],
+    config: &Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["add", kind, target, webhook_url, rest @ ..] if rest.len() <= 1 => {
+            if *kind != "player" && *kind != "clan" {
+                return Err(format!("Unknown watch kind {}, expected player or clan", kind).into());
+            }
+            if !webhook_url.starts_with("http://") && !webhook_url.starts_with("https://") {
+                return Err(format!("Invalid webhook url {}", webhook_url).into());
+            }
+            let cooldown = match rest.first() {
+                Some(value) => value
+                    .parse()
+                    .map_err(|_| format!("Invalid number for cooldown: {}", value))?,
+                None => config.watch_cooldown,
+            };
+            conn.execute(
+                "INSERT INTO watchlist (kind, target, webhook_url, cooldown, created) VALUES (?, ?, ?, ?, ?)",
+                params![kind, target, webhook_url, cooldown, chrono::Utc::now().timestamp() / 60],
+            )?;
+            println!("Added watch {}", conn.last_insert_rowid());
+        }
+        ["list"] => {
+            for watch in load_watches(conn)? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}m",
+                    watch.id, watch.kind, watch.target, watch.webhook_url, watch.cooldown
+                );
+            }
+        }
+        ["remove", id] => {
+            let id: i64 = id.parse().map_err(|_| format!("Invalid watch id {}", id))?;
+            if conn.execute("DELETE FROM watchlist WHERE id = ?", params![id])? == 0 {
+                return Err(format!("No watch with id {}", id).into());
+            }
+            println!("Removed watch {}", id);
+        }
+        _ => {
+            eprintln!("{}", COMMAND_USAGE);
+            return Err("Invalid watch command".into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::Router;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{migrations, model};
+
+    type Requests = Arc<Mutex<Vec<String>>>;
+
+    /// A webhook stand-in that fails the first `failures` requests, its url and every body it got
+    async fn webhook(failures: usize) -> (String, Requests) {
+        let requests: Requests = Arc::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let received = requests.clone();
+        let app = Router::new().route(
+            "/hook",
+            post(move |body: String| {
+                let received = received.clone();
+                async move {
+                    let mut received = received.lock().unwrap();
+                    received.push(body);
+                    if received.len() <= failures {
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    } else {
+                        StatusCode::OK
+                    }
+                }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}/hook", address), requests)
+    }
+
+    /// Waits for the spawned deliveries until `count` requests arrived or a second passed
+    async fn received(requests: &Requests, count: usize) -> Vec<String> {
+        for _ in 0..100 {
+            if requests.lock().unwrap().len() >= count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        requests.lock().unwrap().clone()
+    }
+
+    fn location(address: &str) -> Location {
+        Location {
+            address: address.to_string(),
+            name: "DDNet".to_string(),
+            map: "Multeasymap".to_string(),
+            region: "eu:de".to_string(),
+        }
+    }
+
+    fn servers(players: &[&str]) -> ServerList {
+        let clients: Vec<String> = players
+            .iter()
+            .map(|name| format!(r#"{{"name":"{}","clan":"","country":-1}}"#, name))
+            .collect();
+        let body = format!(
+            r#"{{"servers":[{{"addresses":["tw-0.6+udp://1.1.1.1:8303"],"location":"eu:de","info":{{"name":"DDNet","map":{{"name":"Multeasymap"}},"clients":[{}]}}}}]}}"#,
+            clients.join(",")
+        );
+        model::parse(&body).unwrap().0
+    }
+
+    #[test]
+    fn debounce_holds_back_a_quick_reconnect() {
+        let online = location("1.1.1.1:8303");
+        let other = location("2.2.2.2:8303");
+        let mut presence = Presence {
+            confirmed: Some(online.clone()),
+            ..Presence::default()
+        };
+
+        // gone for one tick and back
+        assert_eq!(presence.observe(None, 2), None);
+        assert_eq!(presence.observe(Some(&online), 2), None);
+        assert!(presence.pending.is_none());
+
+        // flapping between offline and another server never holds
+        assert_eq!(presence.observe(None, 2), None);
+        assert_eq!(presence.observe(Some(&other), 2), None);
+        assert_eq!(presence.observe(None, 2), None);
+
+        assert_eq!(presence.observe(None, 2), Some("offline"));
+        assert!(presence.pending.is_none());
+
+        presence.confirmed = None;
+        assert_eq!(presence.observe(Some(&other), 1), Some("online"));
+        presence.confirmed = Some(other);
+        assert_eq!(presence.observe(Some(&online), 1), Some("server_changed"));
+    }
+
+    #[tokio::test]
+    async fn cooldown_suppresses_notifications() {
+        let (url, requests) = webhook(0).await;
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::run(&mut conn, false).unwrap();
+        conn.execute(
+            "INSERT INTO watchlist (kind, target, webhook_url, cooldown, created) VALUES ('player', 'nameless tee', ?, 10, 0)",
+            params![url],
+        )
+        .unwrap();
+        let config = Config {
+            watch_debounce: 1,
+            retries: 0,
+            ..Config::default()
+        };
+        let mut watchers = Watchers::new(Client::new());
+        let online = servers(&["nameless tee", "brainless tee"]);
+        let offline = servers(&["brainless tee"]);
+
+        // baseline, then gone
+        watchers.update(&conn, &online, 0, &config).unwrap();
+        watchers.update(&conn, &offline, 1, &config).unwrap();
+        assert_eq!(received(&requests, 1).await.len(), 1);
+
+        // back and gone again inside the cooldown, nothing to announce
+        watchers.update(&conn, &online, 2, &config).unwrap();
+        watchers.update(&conn, &offline, 5, &config).unwrap();
+        watchers.update(&conn, &offline, 11, &config).unwrap();
+        assert_eq!(received(&requests, 2).await.len(), 1);
+
+        // back and gone again inside the next cooldown, the offline is sent once it's over
+        watchers.update(&conn, &online, 12, &config).unwrap();
+        watchers.update(&conn, &offline, 13, &config).unwrap();
+        watchers.update(&conn, &offline, 20, &config).unwrap();
+        assert_eq!(received(&requests, 3).await.len(), 2);
+        watchers.update(&conn, &offline, 22, &config).unwrap();
+        let bodies = received(&requests, 3).await;
+        let events: Vec<(String, i64)> = bodies
+            .iter()
+            .map(|body| {
+                let value: serde_json::Value = serde_json::from_str(body).unwrap();
+                (
+                    value["event"].as_str().unwrap().to_string(),
+                    value["time"].as_i64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                ("offline".to_string(), 60000),
+                ("online".to_string(), 12 * 60000),
+                ("offline".to_string(), 22 * 60000)
+            ]
+        );
+        // the held back offline is from where the player was announced last
+        let last: serde_json::Value = serde_json::from_str(&bodies[2]).unwrap();
+        assert_eq!(last["previous"]["address"], "tw-0.6+udp://1.1.1.1:8303");
+        assert!(last["server"].is_null());
+    }
+
+    #[tokio::test]
+    async fn deliver_retries_a_failing_webhook() {
+        let (url, requests) = webhook(1).await;
+        deliver(Client::new(), url, "{}".to_string(), 2, 1).await;
+        assert_eq!(*requests.lock().unwrap(), ["{}", "{}"]);
+
+        // gives up after the retries
+        let (url, requests) = webhook(usize::MAX).await;
+        deliver(Client::new(), url, "{}".to_string(), 2, 1).await;
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+}
@@ -1,5 +1,6 @@
 import { numberToSub } from '$lib/helpers';
 import { encodeAsciiURIComponent } from '$lib/link';
+import { getCoplayPartners } from '$lib/server/ddtracker';
 import { regionalRanks } from '$lib/server/fetches/ranks';
 import { allowedText } from '$lib/server/filter';
 import { getPlayer } from '$lib/server/players';
@@ -84,6 +85,13 @@ export const handlePoints: Handler = async ({ platform, user, reply, args }) =>
 			})
 	];
 
+	const partners = getCoplayPartners(player.name, 3)
+		.map((partner) => partner.name)
+		.filter((name) => allowedText(name));
+	if (partners.length > 0) {
+		lines.push(`🤝 常一起玩: ${partners.join(', ')}`);
+	}
+
 	return await reply.textLink(lines.join('\n'), {
 		label: `🔗 玩家详情`,
 		prefix: '详情点击：',
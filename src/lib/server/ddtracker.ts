@@ -2,24 +2,149 @@ import { building } from '$app/environment';
 import { env } from '$env/dynamic/private';
 import sqlite, { type Statement, type Database } from 'bun:sqlite';
 
+/** Schema version of the ddtracker database (its `user_version`) these queries are written for */
+export const DDTRACKER_SCHEMA_VERSION = 12;
+
 let db: Database | null = null;
 
-let dbGetSkinInRegion: Statement<{ current_skin: string }, [string, string]> | null = null;
-let dbGetSkinInRegionPrefix: Statement<{ current_skin: string }, [string, string]> | null = null;
-let dbGetSkin: Statement<{ current_skin: string }, [string]> | null = null;
+// the player (?1) and every name linked to it by confirmed aliases, as the `aliases` table. queries
+// that start with it merge the histories of renamed players
+const ALIASES = `WITH RECURSIVE aliases(name) AS (
+	SELECT ?1
+	UNION
+	SELECT CASE WHEN d.old_name = a.name THEN d.new_name ELSE d.old_name END
+	FROM alias_decisions d JOIN aliases a ON d.old_name = a.name OR d.new_name = a.name
+	WHERE d.status = 'confirmed'
+)`;
+let dbGetAliases: Statement<{ name: string }, [string]> | null = null;
+
+type SkinRow = { skin_name: string | null; body: number | null; feet: number | null };
+let dbGetSkinInRegion: Statement<SkinRow, [string, string]> | null = null;
+let dbGetSkinInRegionPrefix: Statement<SkinRow, [string, string]> | null = null;
+let dbGetSkin: Statement<SkinRow, [string]> | null = null;
+let dbGetHomeRegion: Statement<{ region: string }, [string]> | null = null;
+
+type SkinHistoryRow = SkinRow & { region: string; first_seen: number; last_seen: number };
+let dbGetSkinHistory: Statement<SkinHistoryRow, [string]> | null = null;
+let dbGetSkinHistoryInRegion: Statement<SkinHistoryRow, [string, string]> | null = null;
+
+type PopulationRow = {
+	bucket: number;
+	samples: number;
+	players_sum: number;
+	players_max: number;
+	spectators_sum: number;
+	spectators_max: number;
+	people_sum: number | null;
+	people_max: number | null;
+};
+let dbGetPopulation: Statement<PopulationRow, [string, string, string, number]> | null = null;
+
+type DummyLinkRow = {
+	owner: string;
+	dummy: string;
+	joins: number;
+	apart: number;
+	confidence: number;
+	first_seen: number;
+	last_seen: number;
+};
+let dbGetDummyLinks: Statement<DummyLinkRow, [string, number]> | null = null;
+
+type ClanRow = { name: string; clan: string; since: number; last_seen: number };
+let dbGetClanRoster: Statement<ClanRow, [string]> | null = null;
+let dbGetPlayerClans: Statement<
+	{ clan: string; first_seen: number; last_seen: number },
+	[string]
+> | null = null;
+
+type TrendingSkinRow = { skin: string; players: number; previous: number };
+let dbGetTrendingSkins: Statement<TrendingSkinRow, [number, number, string, number, number]> | null =
+	null;
+let dbGetSkinColors: Statement<
+	{ color: number; players: number },
+	[string, string, number, number]
+> | null = null;
+
+type ServerRow = {
+	address: string;
+	addresses: string;
+	region: string | null;
+	name: string;
+	game_type: string;
+	version: string;
+	passworded: number;
+	max_clients: number;
+	max_players: number;
+	first_seen: number;
+	last_seen: number;
+};
+let dbGetServer: Statement<ServerRow, [string]> | null = null;
+type ServerChangeRow = { time: number; field: string; old_value: string; new_value: string };
+let dbGetServerChanges: Statement<ServerChangeRow, [string]> | null = null;
+let dbCountTicks: Statement<{ ticks: number }, [number]> | null = null;
+type ServerUptimeRow = { address: string; name: string; region: string; listed: number };
+let dbGetServerUptimes: Statement<ServerUptimeRow, [number, string]> | null = null;
+
+type MapChangeRow = {
+	time: number;
+	previous_map: string;
+	map: string;
+	previous_players: number;
+	players: number;
+};
+let dbGetMapChanges: Statement<MapChangeRow, [string, number, number]> | null = null;
+type MapStintStatsRow = {
+	map: string;
+	stints: number;
+	minutes: number;
+	ticks: number;
+	players_sum: number;
+	first_players: number;
+	last_players: number;
+	players_max: number;
+};
+let dbGetMapStintStats: Statement<MapStintStatsRow, [string, number]> | null = null;
+
+type CoplayRow = { name: string; weight: number; minutes: number; last_seen: number };
+let dbGetCoplayPartners: Statement<CoplayRow, [string, number]> | null = null;
+let dbGetSuggestedTeammates: Statement<{ name: string; score: number }, [string, number]> | null =
+	null;
+
+let dbGetActivity: Statement<{ hour: number; minutes: number }, [string]> | null = null;
+let dbGetPlayerRegions: Statement<
+	{ region: string; minutes: number; last_seen: number },
+	[string]
+> | null = null;
+
+type PlayerTeamRow = {
+	address: string;
+	map: string;
+	start_time: number;
+	end_time: number;
+	members: string;
+};
+let dbGetPlayerTeams: Statement<PlayerTeamRow, [string, number]> | null = null;
+type TeammateRow = { name: string; teams: number; minutes: number };
+let dbGetTeammates: Statement<TeammateRow, [string, string | null, number]> | null = null;
+type MapTeamStatsRow = { map: string; teams: number; minutes: number; size: number };
+let dbGetMapTeamStats: Statement<MapTeamStatsRow, [number, number]> | null = null;
+
+let dbGetMostPlayedMaps: Statement<{ map: string; player_minutes: number }, [number, number]> | null =
+	null;
 
 if (!building) {
 	const ddtrackerPath = env.DDTRACKER_PATH || './cache/ddtracker.db';
 	db = sqlite.open(ddtrackerPath, { readonly: true });
-	dbGetSkinInRegion = db.prepare<{ current_skin: string }, [string, string]>(
-		'SELECT current_skin FROM clients WHERE name = ? AND region = ?'
-	);
-	dbGetSkinInRegionPrefix = db.prepare<{ current_skin: string }, [string, string]>(
-		'SELECT current_skin FROM clients WHERE name LIKE ? AND region LIKE ? ORDER BY current_skin_time DESC LIMIT 1'
-	);
-	dbGetSkin = db.prepare<{ current_skin: string }, [string]>(
-		'SELECT current_skin FROM clients WHERE name = ? ORDER BY current_skin_time DESC LIMIT 1'
-	);
+	const schemaVersion = getSchemaVersion();
+	if (schemaVersion !== DDTRACKER_SCHEMA_VERSION) {
+		// the statements would fail against tables that don't exist yet, leave every lookup empty
+		console.error(
+			`ddtracker database ${ddtrackerPath} is at schema version ${schemaVersion}, expected ${DDTRACKER_SCHEMA_VERSION}, run \`ddtracker migrate\` or update the website. ddtracker lookups are disabled`
+		);
+	} else {
+		prepareStatements(db);
+	}
 
 	process.on('sveltekit:shutdown', async (reason) => {
 		console.log('Shutting down ddtracker...');
@@ -27,17 +152,136 @@ if (!building) {
 	});
 }
 
+function prepareStatements(db: Database) {
+	dbGetSkinInRegion = db.prepare<SkinRow, [string, string]>(
+		'SELECT s.name AS skin_name, s.body, s.feet FROM clients c JOIN skins s ON s.id = c.current_skin_id WHERE c.name = ? AND c.region = ?'
+	);
+	dbGetSkinInRegionPrefix = db.prepare<SkinRow, [string, string]>(
+		'SELECT s.name AS skin_name, s.body, s.feet FROM clients c JOIN skins s ON s.id = c.current_skin_id WHERE c.name LIKE ? AND c.region LIKE ? ORDER BY c.current_skin_time DESC LIMIT 1'
+	);
+	dbGetSkin = db.prepare<SkinRow, [string]>(
+		'SELECT s.name AS skin_name, s.body, s.feet FROM clients c JOIN skins s ON s.id = c.current_skin_id WHERE c.name = ? ORDER BY c.current_skin_time DESC LIMIT 1'
+	);
+	dbGetAliases = db.prepare<{ name: string }, [string]>(
+		`${ALIASES} SELECT name FROM aliases WHERE name != ?1`
+	);
+	dbGetHomeRegion = db.prepare<{ region: string }, [string]>(
+		`${ALIASES} SELECT region FROM player_regions WHERE name IN aliases GROUP BY region ORDER BY SUM(minutes) DESC, MAX(last_seen) DESC LIMIT 1`
+	);
+	dbGetSkinHistory = db.prepare<SkinHistoryRow, [string]>(
+		`${ALIASES} SELECT h.region, s.name AS skin_name, s.body, s.feet, h.first_seen, h.last_seen FROM skin_history h JOIN skins s ON s.id = h.skin_id WHERE h.name IN aliases ORDER BY h.first_seen DESC`
+	);
+	dbGetSkinHistoryInRegion = db.prepare<SkinHistoryRow, [string, string]>(
+		`${ALIASES} SELECT h.region, s.name AS skin_name, s.body, s.feet, h.first_seen, h.last_seen FROM skin_history h JOIN skins s ON s.id = h.skin_id WHERE h.name IN aliases AND h.region LIKE ?2 ORDER BY h.first_seen DESC`
+	);
+	dbGetPopulation = db.prepare<PopulationRow, [string, string, string, number]>(
+		'SELECT bucket, samples, players_sum, players_max, spectators_sum, spectators_max, people_sum, people_max FROM population_rollups WHERE scope = ? AND key = ? AND period = ? AND bucket >= ? ORDER BY bucket'
+	);
+	dbGetDummyLinks = db.prepare<DummyLinkRow, [string, number]>(
+		'SELECT owner, dummy, joins, apart, confidence, first_seen, last_seen FROM dummy_links WHERE (owner = ?1 OR dummy = ?1) AND confidence >= ?2 ORDER BY confidence DESC'
+	);
+	dbGetClanRoster = db.prepare<ClanRow, [string]>(
+		'SELECT name, clan, since, last_seen FROM player_clans WHERE clan = ? ORDER BY last_seen DESC'
+	);
+	dbGetPlayerClans = db.prepare<{ clan: string; first_seen: number; last_seen: number }, [string]>(
+		`${ALIASES} SELECT clan, first_seen, last_seen FROM clan_members WHERE name IN aliases ORDER BY last_seen DESC`
+	);
+	dbGetTrendingSkins = db.prepare<TrendingSkinRow, [number, number, string, number, number]>(
+		'SELECT skin, SUM(CASE WHEN day >= ? THEN players ELSE 0 END) AS players, SUM(CASE WHEN day < ? THEN players ELSE 0 END) AS previous FROM skin_popularity WHERE region LIKE ? AND day >= ? GROUP BY skin ORDER BY players DESC LIMIT ?'
+	);
+	dbGetSkinColors = db.prepare<{ color: number; players: number }, [string, string, number, number]>(
+		'SELECT color, SUM(players) AS players FROM skin_colors WHERE skin = ? AND part = ? AND day >= ? GROUP BY color ORDER BY players DESC LIMIT ?'
+	);
+	dbGetServer = db.prepare<ServerRow, [string]>(
+		'SELECT address, addresses, region, name, game_type, version, passworded, max_clients, max_players, first_seen, last_seen FROM servers WHERE address = ?'
+	);
+	dbGetServerChanges = db.prepare<ServerChangeRow, [string]>(
+		'SELECT time, field, old_value, new_value FROM server_changes WHERE address = ? ORDER BY time DESC'
+	);
+	dbCountTicks = db.prepare<{ ticks: number }, [number]>(
+		'SELECT COUNT(*) AS ticks FROM ticks WHERE time >= ?'
+	);
+	dbGetServerUptimes = db.prepare<ServerUptimeRow, [number, string]>(
+		'SELECT s.address, s.name, s.region, (SELECT COUNT(*) FROM server_listings l JOIN ticks t ON t.time BETWEEN l.start_time AND l.end_time WHERE l.address = s.address AND l.end_time >= ?1 AND t.time >= ?1) AS listed FROM servers s WHERE s.region LIKE ?2 AND s.last_seen >= ?1 ORDER BY listed DESC'
+	);
+	dbGetMapChanges = db.prepare<MapChangeRow, [string, number, number]>(
+		'SELECT time, previous_map, map, previous_players, players FROM map_changes WHERE address = ? AND time >= ? ORDER BY time DESC LIMIT ?'
+	);
+	dbGetMapStintStats = db.prepare<MapStintStatsRow, [string, number]>(
+		'SELECT map, COUNT(*) AS stints, SUM(end_time - start_time + 1) AS minutes, SUM(ticks) AS ticks, SUM(players_sum) AS players_sum, AVG(first_players) AS first_players, AVG(last_players) AS last_players, MAX(players_max) AS players_max FROM map_stints WHERE address = ? AND end_time >= ? GROUP BY map ORDER BY minutes DESC'
+	);
+	dbGetCoplayPartners = db.prepare<CoplayRow, [string, number]>(
+		'SELECT b AS name, weight, minutes, last_seen FROM coplay WHERE a = ?1 UNION ALL SELECT a AS name, weight, minutes, last_seen FROM coplay WHERE b = ?1 ORDER BY weight DESC LIMIT ?2'
+	);
+	// partners of the player's top partners, weighted by both edges, leaving out anyone already played with
+	dbGetSuggestedTeammates = db.prepare<{ name: string; score: number }, [string, number]>(
+		`WITH partners AS (
+			SELECT b AS name, weight FROM coplay WHERE a = ?1 UNION ALL SELECT a AS name, weight FROM coplay WHERE b = ?1
+			ORDER BY weight DESC LIMIT 20
+		), candidates AS (
+			SELECT c.b AS name, p.weight * c.weight AS score FROM partners p JOIN coplay c ON c.a = p.name
+			UNION ALL
+			SELECT c.a AS name, p.weight * c.weight AS score FROM partners p JOIN coplay c ON c.b = p.name
+		)
+		SELECT name, SUM(score) AS score FROM candidates
+		WHERE name != ?1 AND name NOT IN (SELECT b FROM coplay WHERE a = ?1 UNION ALL SELECT a FROM coplay WHERE b = ?1)
+		GROUP BY name ORDER BY score DESC LIMIT ?2`
+	);
+	dbGetActivity = db.prepare<{ hour: number; minutes: number }, [string]>(
+		`${ALIASES} SELECT hour, SUM(minutes) AS minutes FROM player_activity WHERE name IN aliases GROUP BY hour`
+	);
+	dbGetPlayerRegions = db.prepare<
+		{ region: string; minutes: number; last_seen: number },
+		[string]
+	>(
+		`${ALIASES} SELECT region, SUM(minutes) AS minutes, MAX(last_seen) AS last_seen FROM player_regions WHERE name IN aliases GROUP BY region ORDER BY minutes DESC`
+	);
+	dbGetPlayerTeams = db.prepare<PlayerTeamRow, [string, number]>(
+		`${ALIASES} SELECT t.address, t.map, t.start_time, t.end_time, (SELECT json_group_array(m.name) FROM team_members m WHERE m.team_id = t.id) AS members FROM team_members me JOIN teams t ON t.id = me.team_id WHERE me.name IN aliases ORDER BY t.end_time DESC LIMIT ?2`
+	);
+	dbGetTeammates = db.prepare<TeammateRow, [string, string | null, number]>(
+		`${ALIASES} SELECT other.name, COUNT(DISTINCT t.id) AS teams, SUM(MAX(0, MIN(me.last_seen, other.last_seen) - MAX(me.first_seen, other.first_seen) + 1)) AS minutes FROM team_members me JOIN teams t ON t.id = me.team_id JOIN team_members other ON other.team_id = me.team_id AND other.name NOT IN aliases WHERE me.name IN aliases AND (?2 IS NULL OR t.map = ?2) GROUP BY other.name ORDER BY minutes DESC LIMIT ?3`
+	);
+	dbGetMapTeamStats = db.prepare<MapTeamStatsRow, [number, number]>(
+		'SELECT t.map, COUNT(*) AS teams, AVG(t.end_time - t.start_time + 1) AS minutes, AVG((SELECT COUNT(*) FROM team_members m WHERE m.team_id = t.id)) AS size FROM teams t WHERE t.end_time >= ? GROUP BY t.map ORDER BY teams DESC LIMIT ?'
+	);
+	dbGetMostPlayedMaps = db.prepare<{ map: string; player_minutes: number }, [number, number]>(
+		'SELECT map, SUM(player_minutes) AS player_minutes FROM map_playtime WHERE day >= ? GROUP BY map ORDER BY player_minutes DESC LIMIT ?'
+	);
+}
+
+/** `user_version` of the ddtracker database, null when it isn't open */
+export function getSchemaVersion() {
+	if (!db) return null;
+	return db.query<{ user_version: number }, []>('PRAGMA user_version').get()?.user_version ?? null;
+}
+
 export type DDNetSkin = { n: string; b?: number; f?: number };
 
+/** same shape the skins used to be stored in, missing fields are left out */
+const toSkin = (row: SkinRow) => {
+	const skin = {} as DDNetSkin;
+	if (row.skin_name !== null) skin.n = row.skin_name;
+	if (row.body !== null) skin.b = row.body;
+	if (row.feet !== null) skin.f = row.feet;
+	return skin;
+};
+
 export const getSkin = (name: string, region: string | null = null) => {
 	if (!db || !dbGetSkinInRegion || !dbGetSkinInRegionPrefix || !dbGetSkin) return null;
 
 	if (!region) {
+		// the skin from where the player usually plays, the latest one anywhere otherwise
+		const home = dbGetHomeRegion?.get(name);
+		const atHome = home ? dbGetSkinInRegion.get(name, home.region) : null;
+		if (atHome) {
+			return toSkin(atHome);
+		}
 		const result = dbGetSkin.get(name);
 		if (!result) {
 			return null;
 		}
-		return JSON.parse(result.current_skin) as DDNetSkin;
+		return toSkin(result);
 	}
 
 	const location = region.split(':');
@@ -46,12 +290,366 @@ export const getSkin = (name: string, region: string | null = null) => {
 		if (!result) {
 			return null;
 		}
-		return JSON.parse(result.current_skin) as DDNetSkin;
+		return toSkin(result);
 	} else {
 		const result = dbGetSkinInRegionPrefix.get(name, `${region}%`);
 		if (!result) {
 			return null;
 		}
-		return JSON.parse(result.current_skin) as DDNetSkin;
+		return toSkin(result);
+	}
+};
+
+export type DDNetSkinHistoryEntry = {
+	region: string;
+	skin: DDNetSkin;
+	/** unix timestamp in milliseconds */
+	firstSeen: number;
+	/** unix timestamp in milliseconds */
+	lastSeen: number;
+};
+
+/** skin timeline of a player, newest first. region can be a full region or a prefix like `as` */
+export const getSkinHistory = (name: string, region: string | null = null) => {
+	if (!db || !dbGetSkinHistory || !dbGetSkinHistoryInRegion) return [];
+
+	const rows = region
+		? dbGetSkinHistoryInRegion.all(name, region.split(':').length >= 2 ? region : `${region}%`)
+		: dbGetSkinHistory.all(name);
+
+	return rows.map(
+		(row) =>
+			({
+				region: row.region,
+				skin: toSkin(row),
+				firstSeen: row.first_seen * 60000,
+				lastSeen: row.last_seen * 60000
+			}) satisfies DDNetSkinHistoryEntry
+	);
+};
+
+export type DDNetPopulationPoint = {
+	/** unix timestamp in milliseconds, start of the hour or day */
+	time: number;
+	avgPlayers: number;
+	maxPlayers: number;
+	avgSpectators: number;
+	maxSpectators: number;
+	/** players and spectators without likely dummies, null for buckets from before dummies were tracked */
+	avgPeople: number | null;
+	maxPeople: number | null;
+};
+
+/**
+ * online counts of a server (by address) or a region (like `as:cn`), rolled up by hour or day.
+ * since is a unix timestamp in milliseconds
+ */
+export const getPopulationHistory = (
+	scope: 'server' | 'region',
+	key: string,
+	period: 'hour' | 'day',
+	since: number
+) => {
+	if (!db || !dbGetPopulation) return [];
+
+	return dbGetPopulation.all(scope, key, period, Math.floor(since / 60000)).map(
+		(row) =>
+			({
+				time: row.bucket * 60000,
+				avgPlayers: row.players_sum / row.samples,
+				maxPlayers: row.players_max,
+				avgSpectators: row.spectators_sum / row.samples,
+				maxSpectators: row.spectators_max,
+				avgPeople: row.people_sum === null ? null : row.people_sum / row.samples,
+				maxPeople: row.people_max
+			}) satisfies DDNetPopulationPoint
+	);
+};
+
+/**
+ * skins ranked by player-days (distinct players per day, summed) in a region since the given unix
+ * timestamp in milliseconds. region can be a full region or a prefix like `as`. previous is the same
+ * count over the period of the same length right before, to tell what is trending
+ */
+export const getTrendingSkins = (region: string, since: number, limit = 20) => {
+	if (!db || !dbGetTrendingSkins) return [];
+
+	const sinceMinute = Math.floor(since / 60000);
+	const sinceDay = sinceMinute - (sinceMinute % 1440);
+	const nowMinute = Math.floor(Date.now() / 60000);
+	// the period runs through the end of today
+	const length = nowMinute - (nowMinute % 1440) + 1440 - sinceDay;
+	return dbGetTrendingSkins
+		.all(
+			sinceDay,
+			sinceDay,
+			region.split(':').length >= 2 ? region : `${region}%`,
+			sinceDay - length,
+			limit
+		)
+		.map((row) => ({ skin: row.skin, playerDays: row.players, previousPlayerDays: row.previous }));
+};
+
+/**
+ * most used body or feet colors of a skin by player-days since the given unix timestamp in
+ * milliseconds. colors are ddnet's packed hsl, the center of a bucket of similar colors
+ */
+export const getSkinColors = (skin: string, part: 'body' | 'feet', since: number, limit = 20) => {
+	if (!db || !dbGetSkinColors) return [];
+
+	const sinceMinute = Math.floor(since / 60000);
+	return dbGetSkinColors
+		.all(skin, part, sinceMinute - (sinceMinute % 1440), limit)
+		.map((row) => ({ color: row.color, playerDays: row.players }));
+};
+
+/** latest known metadata of a server by its first address */
+export const getServer = (address: string) => {
+	if (!db || !dbGetServer) return null;
+
+	const row = dbGetServer.get(address);
+	if (!row) return null;
+	return {
+		address: row.address,
+		addresses: JSON.parse(row.addresses) as string[],
+		region: row.region,
+		name: row.name,
+		gameType: row.game_type,
+		version: row.version,
+		passworded: row.passworded !== 0,
+		maxClients: row.max_clients,
+		maxPlayers: row.max_players,
+		/** unix timestamp in milliseconds */
+		firstSeen: row.first_seen * 60000,
+		/** unix timestamp in milliseconds */
+		lastSeen: row.last_seen * 60000
+	};
+};
+
+/** renames and gametype changes of a server, newest first */
+export const getServerChanges = (address: string) => {
+	if (!db || !dbGetServerChanges) return [];
+
+	return dbGetServerChanges.all(address).map((row) => ({
+		/** unix timestamp in milliseconds */
+		time: row.time * 60000,
+		field: row.field as 'name' | 'game_type',
+		from: row.old_value,
+		to: row.new_value
+	}));
+};
+
+/**
+ * share of ddtracker's ticks since the given unix timestamp in milliseconds in which each server of
+ * a region was on the master list. region can be a full region or a prefix like `as`
+ */
+export const getServerUptimes = (region: string, since: number) => {
+	if (!db || !dbCountTicks || !dbGetServerUptimes) return [];
+
+	const sinceMinute = Math.floor(since / 60000);
+	const ticks = dbCountTicks.get(sinceMinute)?.ticks ?? 0;
+	if (ticks === 0) return [];
+	return dbGetServerUptimes
+		.all(sinceMinute, region.split(':').length >= 2 ? region : `${region}%`)
+		.map((row) => ({
+			address: row.address,
+			name: row.name,
+			region: row.region,
+			uptime: row.listed / ticks
+		}));
+};
+
+/** map switches on a server since the given unix timestamp in milliseconds, newest first */
+export const getMapChanges = (address: string, since: number, limit = 100) => {
+	if (!db || !dbGetMapChanges) return [];
+
+	return dbGetMapChanges.all(address, Math.floor(since / 60000), limit).map((row) => ({
+		/** unix timestamp in milliseconds */
+		time: row.time * 60000,
+		from: row.previous_map,
+		to: row.map,
+		/** players on the old map in its last tick */
+		playersBefore: row.previous_players,
+		/** players on the new map in its first tick */
+		playersAfter: row.players
+	}));
+};
+
+/**
+ * how long each map stays up on a server and how many players it keeps, over the stints that ended
+ * since the given unix timestamp in milliseconds. players count players, not spectators
+ */
+export const getMapStintStats = (address: string, since: number) => {
+	if (!db || !dbGetMapStintStats) return [];
+
+	return dbGetMapStintStats.all(address, Math.floor(since / 60000)).map((row) => ({
+		map: row.map,
+		stints: row.stints,
+		avgMinutes: row.minutes / row.stints,
+		avgPlayers: row.players_sum / row.ticks,
+		avgStartPlayers: row.first_players,
+		avgEndPlayers: row.last_players,
+		maxPlayers: row.players_max
+	}));
+};
+
+// co-play weights are stored scaled up by 2^((t - EPOCH) / HALF_LIFE), must match coplay.rs
+const COPLAY_EPOCH = 28928160;
+const COPLAY_HALF_LIFE = 30 * 24 * 60;
+const coplayScale = () => Math.pow(2, (Date.now() / 60000 - COPLAY_EPOCH) / COPLAY_HALF_LIFE);
+
+/**
+ * players the player most often plays with. weight is minutes together (teammates count triple),
+ * halved every 30 days, minutes is the plain count of minutes seen on the same server
+ */
+export const getCoplayPartners = (name: string, limit = 10) => {
+	if (!db || !dbGetCoplayPartners) return [];
+
+	const scale = coplayScale();
+	return dbGetCoplayPartners.all(name, limit).map((row) => ({
+		name: row.name,
+		weight: row.weight / scale,
+		minutes: row.minutes,
+		/** unix timestamp in milliseconds */
+		lastSeen: row.last_seen * 60000
+	}));
+};
+
+/** players the player hasn't played with yet but their regular partners have, best match first */
+export const getSuggestedTeammates = (name: string, limit = 10) => {
+	if (!db || !dbGetSuggestedTeammates) return [];
+
+	const rows = dbGetSuggestedTeammates.all(name, limit);
+	const best = rows[0]?.score || 1;
+	return rows.map((row) => ({ name: row.name, score: row.score / best }));
+};
+
+/** other names of the player, linked by aliases an admin confirmed */
+export const getAliases = (name: string) => {
+	if (!db || !dbGetAliases) return [];
+
+	return dbGetAliases.all(name).map((row) => row.name);
+};
+
+// must match DUMMY_CONFIDENCE in dummies.rs
+const DUMMY_CONFIDENCE = 0.75;
+
+/**
+ * likely owner/dummy links the player is part of, either side. pass a lower minConfidence to see
+ * candidates that aren't counted as dummies yet
+ */
+export const getDummyLinks = (name: string, minConfidence = DUMMY_CONFIDENCE) => {
+	if (!db || !dbGetDummyLinks) return [];
+
+	return dbGetDummyLinks.all(name, minConfidence).map((row) => ({
+		owner: row.owner,
+		dummy: row.dummy,
+		joins: row.joins,
+		apart: row.apart,
+		confidence: row.confidence,
+		/** unix timestamp in milliseconds */
+		firstSeen: row.first_seen * 60000,
+		/** unix timestamp in milliseconds */
+		lastSeen: row.last_seen * 60000
+	}));
+};
+
+/** the region the player spent the most minutes in */
+export const getHomeRegion = (name: string) => {
+	if (!db || !dbGetHomeRegion) return null;
+
+	return dbGetHomeRegion.get(name)?.region ?? null;
+};
+
+/** minutes the player was online per region, most first */
+export const getPlayerRegions = (name: string) => {
+	if (!db || !dbGetPlayerRegions) return [];
+
+	return dbGetPlayerRegions.all(name).map((row) => ({
+		region: row.region,
+		minutes: row.minutes,
+		/** unix timestamp in milliseconds */
+		lastSeen: row.last_seen * 60000
+	}));
+};
+
+/** minutes online per hour of the week in UTC, index 0 is monday 00:00 */
+export const getActivityHeatmap = (name: string) => {
+	if (!db || !dbGetActivity) return null;
+
+	const hours = new Array<number>(168).fill(0);
+	for (const row of dbGetActivity.all(name)) {
+		hours[row.hour] = row.minutes;
 	}
+	return hours;
+};
+
+/** ddrace teams the player was in, newest first */
+export const getPlayerTeams = (name: string, limit = 20) => {
+	if (!db || !dbGetPlayerTeams) return [];
+
+	return dbGetPlayerTeams.all(name, limit).map((row) => ({
+		address: row.address,
+		map: row.map,
+		/** unix timestamp in milliseconds */
+		startTime: row.start_time * 60000,
+		/** unix timestamp in milliseconds */
+		endTime: row.end_time * 60000,
+		members: JSON.parse(row.members) as string[]
+	}));
+};
+
+/** who the player teamed with, on any map or only on the given one, most minutes together first */
+export const getTeammates = (name: string, map: string | null = null, limit = 20) => {
+	if (!db || !dbGetTeammates) return [];
+
+	return dbGetTeammates.all(name, map, limit);
+};
+
+/** maps by how many ddrace teams formed on them since the given unix timestamp in milliseconds */
+export const getMapTeamStats = (since: number, limit = 20) => {
+	if (!db || !dbGetMapTeamStats) return [];
+
+	return dbGetMapTeamStats.all(Math.floor(since / 60000), limit).map((row) => ({
+		map: row.map,
+		teams: row.teams,
+		avgMinutes: row.minutes,
+		avgSize: row.size
+	}));
+};
+
+/** maps ranked by player-minutes since the given unix timestamp in milliseconds (counted by day) */
+export const getMostPlayedMaps = (since: number, limit = 20) => {
+	if (!db || !dbGetMostPlayedMaps) return [];
+
+	const sinceMinute = Math.floor(since / 60000);
+	return dbGetMostPlayedMaps
+		.all(sinceMinute - (sinceMinute % 1440), limit)
+		.map((row) => ({ map: row.map, playerMinutes: row.player_minutes }));
+};
+
+/** players currently wearing the clan tag, most recently seen first */
+export const getClanRoster = (clan: string) => {
+	if (!db || !dbGetClanRoster) return [];
+
+	return dbGetClanRoster.all(clan).map((row) => ({
+		name: row.name,
+		/** unix timestamp in milliseconds */
+		since: row.since * 60000,
+		/** unix timestamp in milliseconds */
+		lastSeen: row.last_seen * 60000
+	}));
+};
+
+/** every clan the player has been seen in, most recent first */
+export const getPlayerClans = (name: string) => {
+	if (!db || !dbGetPlayerClans) return [];
+
+	return dbGetPlayerClans.all(name).map((row) => ({
+		clan: row.clan,
+		/** unix timestamp in milliseconds */
+		firstSeen: row.first_seen * 60000,
+		/** unix timestamp in milliseconds */
+		lastSeen: row.last_seen * 60000
+	}));
 };